@@ -0,0 +1,324 @@
+//! Swapping personal data in traces for fakes.
+//!
+//! Each fake is made from a hash of the real value, so the same email becomes
+//! the same fake everywhere. That way, a response that echoes the request
+//! still matches it. Emails are found wherever they are. IPv4 addresses are
+//! only looked for in headers like `X-Forwarded-For`, because elsewhere
+//! they're hard to tell apart from version numbers. Names, addresses and other
+//! personal fields are picked out by JSON path in `.cargo-ngrok/config.toml`,
+//! with `*` matching any key or array index:
+//!
+//! ```toml
+//! [anonymise]
+//! salt = "something that isn't committed"
+//! names = ["user.name", "friends.*.name"]
+//! ips = ["user.last_login_ip"]
+//! text = ["user.address"]
+//! ```
+
+use crate::config::string_list;
+use crate::hash::fnv1a;
+use crate::http::{self, Headers};
+use crate::list::RequestTrace;
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use toml_edit::DocumentMut;
+
+lazy_static! {
+    static ref EMAIL: Regex =
+        Regex::new(r"(?i)\b[a-z0-9._+-]+(@|%40)[a-z0-9-]+(\.[a-z0-9-]+)*\.[a-z]{2,}\b").unwrap();
+    static ref IPV4: Regex = Regex::new(r"\b(\d{1,3})\.(\d{1,3})\.(\d{1,3})\.(\d{1,3})\b").unwrap();
+    static ref FAKE_EMAIL: Regex = Regex::new(r"\buser-[0-9a-f]{8}(@|%40)example\.com\b").unwrap();
+    static ref FAKE_IPV4: Regex =
+        Regex::new(r"\b(192\.0\.2|198\.51\.100|203\.0\.113)\.\d{1,3}\b").unwrap();
+    static ref FAKE_TEXT: Regex = Regex::new(r"\banon-[0-9a-f]{8}\b").unwrap();
+}
+
+/// Headers that hold the client's IP address.
+const ADDRESS_HEADERS: &[&str] = &["x-forwarded-for", "x-real-ip", "forwarded"];
+
+/// The networks that are reserved for documentation, so fakes can't clash
+/// with real addresses.
+const FAKE_NETWORKS: &[&str] = &["192.0.2", "198.51.100", "203.0.113"];
+
+const FIRST_NAMES: &[&str] = &[
+    "Alex", "Billie", "Charlie", "Dana", "Eden", "Frankie", "Gray", "Harper", "Indigo", "Jesse",
+    "Kai", "Lee", "Morgan", "Noel", "Oakley", "Parker",
+];
+
+const LAST_NAMES: &[&str] = &[
+    "Archer", "Baker", "Carter", "Dalton", "Ellis", "Fisher", "Garner", "Hayes", "Irving",
+    "Jordan", "Keller", "Lawson", "Mason", "Nolan", "Oliver", "Porter",
+];
+
+#[derive(Debug)]
+pub(crate) struct Anonymiser {
+    /// Mixed into the hashes, so that fakes can't be checked against a list
+    /// of likely emails.
+    salt: String,
+    /// Paths of fields that hold names, split on `.`.
+    names: Vec<Vec<String>>,
+    /// Paths of fields that hold IP addresses.
+    ips: Vec<Vec<String>>,
+    /// Paths of other personal fields.
+    text: Vec<Vec<String>>,
+}
+
+impl Anonymiser {
+    /// Anonymises emails and client IPs, plus anything in the `[anonymise]` table of
+    /// the config.
+    pub(crate) fn from_config(config: &DocumentMut) -> Result<Self> {
+        let salt = match config.get("anonymise").and_then(|table| table.get("salt")) {
+            Some(salt) => salt
+                .as_str()
+                .context("anonymise.salt should be a string")?
+                .to_string(),
+            None => String::new(),
+        };
+        let paths = |key| -> Result<Vec<Vec<String>>> {
+            Ok(string_list(config, "anonymise", key)?
+                .iter()
+                .map(|path| path.split('.').map(String::from).collect())
+                .collect())
+        };
+        Ok(Anonymiser {
+            salt,
+            names: paths("names")?,
+            ips: paths("ips")?,
+            text: paths("text")?,
+        })
+    }
+
+    pub(crate) fn anonymise_trace(&self, trace: RequestTrace) -> Result<RequestTrace> {
+        trace.rewrite(
+            |uri| self.anonymise_emails(uri),
+            |headers, body| self.anonymise_message(headers, body),
+        )
+    }
+
+    fn hash(&self, kind: &str, value: &str) -> u64 {
+        fnv1a(&[self.salt.as_bytes(), kind.as_bytes(), value.as_bytes()])
+    }
+
+    /// Replace every email in `text`.
+    fn anonymise_emails(&self, text: &str) -> String {
+        EMAIL
+            .replace_all(text, |captures: &Captures| {
+                let email = &captures[0];
+                if FAKE_EMAIL.is_match(email) {
+                    return email.to_string();
+                }
+                // `%40` is how `@` looks in a query string.
+                let at = &captures[1];
+                let hash = self.hash("email", &email.to_lowercase().replace("%40", "@"));
+                format!("user-{:08x}{}example.com", hash as u32, at)
+            })
+            .into_owned()
+    }
+
+    /// Replace every IP address in `text`.
+    fn anonymise_ips(&self, text: &str) -> String {
+        IPV4.replace_all(text, |captures: &Captures| {
+            let ip = &captures[0];
+            let octets: Vec<u32> = (1..=4).map(|i| captures[i].parse().unwrap()).collect();
+            let is_address = octets.iter().all(|&octet| octet <= 255);
+            // Loopback and unspecified addresses don't say anything about anyone.
+            if !is_address || octets[0] == 127 || octets[0] == 0 || FAKE_IPV4.is_match(ip) {
+                return ip.to_string();
+            }
+            let hash = self.hash("ip", ip);
+            let network = FAKE_NETWORKS[(hash % FAKE_NETWORKS.len() as u64) as usize];
+            format!("{}.{}", network, (hash >> 8) % 256)
+        })
+        .into_owned()
+    }
+
+    fn fake_name(&self, name: &str) -> String {
+        if is_fake_name(name) {
+            return name.to_string();
+        }
+        let hash = self.hash("name", name);
+        format!(
+            "{} {}",
+            FIRST_NAMES[(hash % FIRST_NAMES.len() as u64) as usize],
+            LAST_NAMES[((hash >> 8) % LAST_NAMES.len() as u64) as usize]
+        )
+    }
+
+    fn fake_text(&self, text: &str) -> String {
+        if FAKE_TEXT.is_match(text) {
+            return text.to_string();
+        }
+        format!("anon-{:08x}", self.hash("text", text) as u32)
+    }
+
+    fn anonymise_message(&self, headers: &mut Headers, body: &mut Vec<u8>) {
+        for (name, value) in headers.iter_mut() {
+            let text = self.anonymise_emails(&String::from_utf8_lossy(value));
+            let text = match ADDRESS_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+                true => self.anonymise_ips(&text),
+                false => text,
+            };
+            *value = text.into_bytes();
+        }
+
+        if http::is_json(headers) {
+            if let Ok(json) = serde_json::from_slice::<serde_json::Value>(body) {
+                let mut anonymised = json.clone();
+                self.anonymise_json(&mut anonymised, &mut vec![]);
+                // Re-serializing loses the original layout, so only do it if
+                // we have to.
+                if anonymised != json {
+                    *body =
+                        serde_json::to_vec(&anonymised).expect("serializing a Value can't fail");
+                }
+                return;
+            }
+        }
+        if let Ok(text) = std::str::from_utf8(body) {
+            *body = self.anonymise_emails(text).into_bytes();
+        }
+    }
+
+    fn anonymise_json(&self, value: &mut serde_json::Value, path: &mut Vec<String>) {
+        match value {
+            serde_json::Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    path.push(key.clone());
+                    self.anonymise_json(value, path);
+                    path.pop();
+                }
+            }
+            serde_json::Value::Array(values) => {
+                for (index, value) in values.iter_mut().enumerate() {
+                    path.push(index.to_string());
+                    self.anonymise_json(value, path);
+                    path.pop();
+                }
+            }
+            serde_json::Value::String(text) => {
+                *text = if matches_any(&self.names, path) {
+                    self.fake_name(text)
+                } else if matches_any(&self.ips, path) {
+                    self.anonymise_ips(text)
+                } else if matches_any(&self.text, path) {
+                    self.fake_text(text)
+                } else {
+                    self.anonymise_emails(text)
+                };
+            }
+            _ => {}
+        }
+    }
+}
+
+fn matches_any(patterns: &[Vec<String>], path: &[String]) -> bool {
+    patterns.iter().any(|pattern| {
+        pattern.len() == path.len()
+            && pattern
+                .iter()
+                .zip(path)
+                .all(|(expected, actual)| expected == "*" || expected == actual)
+    })
+}
+
+fn is_fake_name(name: &str) -> bool {
+    match name.split_once(' ') {
+        Some((first, last)) => FIRST_NAMES.contains(&first) && LAST_NAMES.contains(&last),
+        None => false,
+    }
+}
+
+/// Whether `text` has fakes in it that aren't in `request`. The real server
+/// would send the real values, so tests can't expect these.
+pub(crate) fn has_unexplained_fakes(text: &str, request: &str) -> bool {
+    if is_fake_name(text) && !request.contains(text) {
+        return true;
+    }
+    [&*FAKE_EMAIL, &*FAKE_IPV4, &*FAKE_TEXT].iter().any(|fake| {
+        fake.find_iter(text)
+            .any(|found| !request.contains(found.as_str()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn anonymiser() -> Anonymiser {
+        let config = r#"
+            [anonymise]
+            salt = "pepper"
+            names = ["users.*.name"]
+            ips = ["users.*.ip"]
+            text = ["address"]
+            "#;
+        Anonymiser::from_config(&config.parse().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_anonymise_emails_and_ips() {
+        let anonymiser = anonymiser();
+        let text = anonymiser
+            .anonymise_emails("/invite?email=Bob%40Example.org&from=10.1.2.3&to=bob@example.org");
+        assert_eq!(
+            text,
+            "/invite?email=user-8b7d38c8%40example.com&from=10.1.2.3\
+             &to=user-8b7d38c8@example.com"
+        );
+        // Fakes are left alone, so anonymising twice changes nothing.
+        assert_eq!(anonymiser.anonymise_emails(&text), text);
+
+        let text = anonymiser.anonymise_ips("10.1.2.3, 1.2.3.456");
+        assert_eq!(text, "203.0.113.78, 1.2.3.456");
+        assert_eq!(anonymiser.anonymise_ips(&text), text);
+        assert_eq!(anonymiser.anonymise_ips("127.0.0.1:8080"), "127.0.0.1:8080");
+    }
+
+    #[test]
+    fn test_anonymise_message() {
+        let anonymiser = anonymiser();
+        let user_agent = "Mozilla/5.0 (X11; Linux x86_64) Chrome/120.0.0.0 Safari/537.36";
+        let mut headers = vec![
+            ("User-Agent".to_string(), user_agent.as_bytes().to_vec()),
+            ("X-Forwarded-For".to_string(), b"10.1.2.3".to_vec()),
+            ("Content-Type".to_string(), b"application/json".to_vec()),
+        ];
+        let mut body =
+            br#"{"version":"1.2.3.4","users":[{"ip":"10.1.2.3","email":"bob@example.org"}]}"#
+                .to_vec();
+        anonymiser.anonymise_message(&mut headers, &mut body);
+        assert_eq!(headers[0].1, user_agent.as_bytes());
+        assert_eq!(headers[1].1, b"203.0.113.78");
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            r#"{"users":[{"email":"user-8b7d38c8@example.com","ip":"203.0.113.78"}],"version":"1.2.3.4"}"#
+        );
+    }
+
+    #[test]
+    fn test_anonymise_json() {
+        let anonymiser = anonymiser();
+        let mut json = serde_json::json!({
+            "users": [{"name": "Bob Smith", "email": "bob@example.org"}],
+            "address": "1 High Street",
+            "name": "Bob Smith",
+        });
+        anonymiser.anonymise_json(&mut json, &mut vec![]);
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "users": [{"name": "Charlie Jordan", "email": "user-8b7d38c8@example.com"}],
+                "address": "anon-004cdbce",
+                "name": "Bob Smith",
+            })
+        );
+
+        let request = r#"{"email":"user-8b7d38c8@example.com"}"#;
+        assert!(!has_unexplained_fakes("user-8b7d38c8@example.com", request));
+        assert!(has_unexplained_fakes("Charlie Jordan", request));
+        assert!(has_unexplained_fakes("at anon-004cdbce", request));
+        assert!(!has_unexplained_fakes("Bob Smith", request));
+    }
+}
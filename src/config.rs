@@ -0,0 +1,37 @@
+//! Reading `.cargo-ngrok/config.toml`, which has a table for each thing that
+//! can be configured.
+
+use anyhow::{Context, Result};
+use toml_edit::DocumentMut;
+
+pub(crate) const CONFIG_PATH: &str = ".cargo-ngrok/config.toml";
+
+/// The config, or an empty one if there isn't a config file.
+pub(crate) fn read_config() -> Result<DocumentMut> {
+    match std::fs::read_to_string(CONFIG_PATH) {
+        Ok(content) => content
+            .parse()
+            .with_context(|| format!("parsing {:?}", CONFIG_PATH)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(DocumentMut::new()),
+        Err(e) => Err(e).with_context(|| format!("reading {:?}", CONFIG_PATH)),
+    }
+}
+
+/// `table.key`, which should be a list of strings, or an empty list if it
+/// isn't set.
+pub(crate) fn string_list(config: &DocumentMut, table: &str, key: &str) -> Result<Vec<String>> {
+    let error = || {
+        format!(
+            "{}.{} in {:?} should be a list of strings",
+            table, key, CONFIG_PATH
+        )
+    };
+    let array = match config.get(table).and_then(|table| table.get(key)) {
+        Some(item) => item.as_array().with_context(error)?,
+        None => return Ok(vec![]),
+    };
+    array
+        .iter()
+        .map(|value| value.as_str().map(String::from).with_context(error))
+        .collect()
+}
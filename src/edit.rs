@@ -0,0 +1,141 @@
+//! Applying text edits to source code.
+//!
+//! Edits are addressed by `LineColumn`s from syn spans. Lines are 1-based and
+//! columns are counted in chars, so they need converting to byte offsets
+//! before we can touch the source.
+
+use anyhow::Result;
+use proc_macro2::LineColumn;
+
+/// Replace the text between `start` and `end` with `text`.
+#[derive(Debug, Clone)]
+pub(crate) struct Edit {
+    pub(crate) start: LineColumn,
+    pub(crate) end: LineColumn,
+    pub(crate) text: String,
+}
+
+impl Edit {
+    pub(crate) fn insert(at: LineColumn, text: impl Into<String>) -> Self {
+        Edit {
+            start: at,
+            end: at,
+            text: text.into(),
+        }
+    }
+}
+
+/// Apply `edits` to `content`.
+///
+/// Insertions at the same point end up in the order that they were given.
+/// Edits that overlap each other are an error.
+pub(crate) fn apply_edits(content: &str, edits: Vec<Edit>) -> Result<String> {
+    let line_starts = line_starts(content);
+    let mut edits = edits
+        .into_iter()
+        .map(|edit| {
+            let start = byte_offset(content, &line_starts, edit.start)?;
+            let end = byte_offset(content, &line_starts, edit.end)?;
+            if end < start {
+                anyhow::bail!("edit ends before it starts: {:?}", edit);
+            }
+            Ok((start, end, edit))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // Stable, so equal insertion points keep their order.
+    edits.sort_by_key(|&(start, end, _)| (start, end));
+
+    for pair in edits.windows(2) {
+        let (_, previous_end, previous) = &pair[0];
+        let (next_start, _, next) = &pair[1];
+        if previous_end > next_start {
+            anyhow::bail!("overlapping edits: {:?} and {:?}", previous, next);
+        }
+    }
+
+    let mut content = content.to_string();
+    for (start, end, edit) in edits.into_iter().rev() {
+        content.replace_range(start..end, &edit.text);
+    }
+    Ok(content)
+}
+
+fn line_starts(content: &str) -> Vec<usize> {
+    std::iter::once(0)
+        .chain(content.match_indices('\n').map(|(i, _)| i + 1))
+        .collect()
+}
+
+fn byte_offset(content: &str, line_starts: &[usize], location: LineColumn) -> Result<usize> {
+    let line_start = location
+        .line
+        .checked_sub(1)
+        .and_then(|index| line_starts.get(index))
+        .copied()
+        .ok_or_else(|| anyhow::anyhow!("line {} is out of range", location.line))?;
+    let line = content[line_start..].split('\n').next().unwrap_or("");
+    line.char_indices()
+        .map(|(i, _)| i)
+        .chain(std::iter::once(line.len()))
+        .nth(location.column)
+        .map(|i| line_start + i)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "column {} is out of range on line {}",
+                location.column,
+                location.line
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(line: usize, column: usize) -> LineColumn {
+        LineColumn { line, column }
+    }
+
+    #[test]
+    fn test_apply_edits() {
+        assert_eq!(
+            apply_edits(
+                "fn main() {\n    println!(\"héllo\");\n}\n",
+                vec![
+                    Edit::insert(at(2, 22), " // wörld"),
+                    Edit::insert(at(2, 4), "let x = 1;\n    "),
+                    Edit::insert(at(2, 4), "let y = 2;\n    "),
+                    Edit {
+                        start: at(2, 14),
+                        end: at(2, 19),
+                        text: "hola".into(),
+                    },
+                ]
+            )
+            .unwrap(),
+            "fn main() {\n    let x = 1;\n    let y = 2;\n    println!(\"hola\"); // wörld\n}\n"
+        )
+    }
+
+    #[test]
+    fn test_apply_edits_overlapping() {
+        assert!(apply_edits(
+            "fn main() {}\n",
+            vec![
+                Edit {
+                    start: at(1, 0),
+                    end: at(1, 7),
+                    text: "fn foo".into(),
+                },
+                Edit::insert(at(1, 3), "x"),
+            ]
+        )
+        .is_err())
+    }
+
+    #[test]
+    fn test_apply_edits_out_of_range() {
+        assert!(apply_edits("fn main() {}\n", vec![Edit::insert(at(3, 0), "x")]).is_err());
+        assert!(apply_edits("fn main() {}\n", vec![Edit::insert(at(1, 13), "x")]).is_err());
+    }
+}
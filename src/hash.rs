@@ -0,0 +1,18 @@
+//! Hashing that gives the same answer every time, for names and fakes that
+//! shouldn't change between runs.
+
+/// A 64-bit FNV-1a hash of `parts`, which is stable across runs and Rust
+/// versions (unlike `DefaultHasher`).
+pub(crate) fn fnv1a(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            // Keep `["ab", "c"]` and `["a", "bc"]` apart.
+            hash = (hash ^ 0xff).wrapping_mul(0x100_0000_01b3);
+        }
+        for &byte in part.iter() {
+            hash = (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+    hash
+}
@@ -0,0 +1,349 @@
+//! Parsing the raw HTTP messages that ngrok captures.
+//!
+//! Bodies are decoded the way a client would see them: de-chunked, and then
+//! decompressed according to `Content-Encoding`.
+
+use anyhow::{Context, Result};
+use std::io::Read;
+
+/// httparse needs to be told how many headers to expect, so we start with
+/// this many and keep doubling until they fit.
+const INITIAL_HEADER_COUNT: usize = 16;
+const MAX_HEADER_COUNT: usize = 4096;
+
+pub(crate) type Headers = Vec<(String, Vec<u8>)>;
+
+/// A request, as the server would see it.
+#[derive(Debug)]
+pub(crate) struct HttpRequest {
+    start_line: String,
+    headers: Headers,
+    body: Vec<u8>,
+}
+
+impl HttpRequest {
+    pub(crate) fn parse(raw: &[u8]) -> Result<Self> {
+        let (byte_count, headers) = parse_head(raw, parse_request_head)?;
+        let body = decode_body(&headers, &raw[byte_count..])?;
+        Ok(HttpRequest {
+            start_line: start_line(raw),
+            headers,
+            body,
+        })
+    }
+
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+
+    pub(crate) fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub(crate) fn bytes(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// The message parts, for rewriting it.
+    pub(crate) fn parts_mut(&mut self) -> (&mut String, &mut Headers, &mut Vec<u8>) {
+        (&mut self.start_line, &mut self.headers, &mut self.body)
+    }
+
+    pub(crate) fn to_raw(&self) -> Vec<u8> {
+        encode(&self.start_line, &self.headers, &self.body)
+    }
+}
+
+/// A response, as the client would see it.
+#[derive(Debug)]
+pub(crate) struct HttpResponse {
+    start_line: String,
+    headers: Headers,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub(crate) fn parse(raw: &[u8]) -> Result<Self> {
+        let (byte_count, headers) = parse_head(raw, parse_response_head)?;
+        let body = decode_body(&headers, &raw[byte_count..])?;
+        Ok(HttpResponse {
+            start_line: start_line(raw),
+            headers,
+            body,
+        })
+    }
+
+    /// A response whose body has already been de-chunked, like the ones that
+    /// HTTP clients give us.
+    pub(crate) fn from_dechunked(
+        start_line: String,
+        headers: Headers,
+        body: &[u8],
+    ) -> Result<Self> {
+        let body = decode_content(&headers, body.to_vec())?;
+        Ok(HttpResponse {
+            start_line,
+            headers,
+            body,
+        })
+    }
+
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+
+    pub(crate) fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// Whether the `Content-Type` is JSON, like `application/json` or
+    /// `application/problem+json`.
+    pub(crate) fn is_json(&self) -> bool {
+        is_json(&self.headers)
+    }
+
+    pub(crate) fn bytes(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub(crate) fn text(&self) -> Result<&str> {
+        std::str::from_utf8(&self.body).context("response body is not UTF-8")
+    }
+
+    /// The message parts, for rewriting it.
+    pub(crate) fn parts_mut(&mut self) -> (&mut String, &mut Headers, &mut Vec<u8>) {
+        (&mut self.start_line, &mut self.headers, &mut self.body)
+    }
+
+    pub(crate) fn to_raw(&self) -> Vec<u8> {
+        encode(&self.start_line, &self.headers, &self.body)
+    }
+}
+
+fn parse_request_head<'b>(
+    headers: &mut [httparse::Header<'b>],
+    raw: &'b [u8],
+) -> httparse::Result<usize> {
+    httparse::Request::new(headers).parse(raw)
+}
+
+fn parse_response_head<'b>(
+    headers: &mut [httparse::Header<'b>],
+    raw: &'b [u8],
+) -> httparse::Result<usize> {
+    httparse::Response::new(headers).parse(raw)
+}
+
+/// Parse the first line and headers with `parse`, with a big enough header
+/// buffer, and return where the body starts.
+fn parse_head<'b>(
+    raw: &'b [u8],
+    parse: fn(&mut [httparse::Header<'b>], &'b [u8]) -> httparse::Result<usize>,
+) -> Result<(usize, Headers)> {
+    let mut header_count = INITIAL_HEADER_COUNT;
+    loop {
+        let mut headers = vec![httparse::EMPTY_HEADER; header_count];
+        match parse(&mut headers, raw) {
+            Ok(httparse::Status::Complete(byte_count)) => {
+                // httparse fills in the headers that it finds from the start.
+                let headers = headers
+                    .iter()
+                    .take_while(|header| !header.name.is_empty())
+                    .map(|header| (header.name.to_string(), header.value.to_vec()))
+                    .collect();
+                return Ok((byte_count, headers));
+            }
+            Ok(httparse::Status::Partial) => anyhow::bail!("HTTP message was partial"),
+            Err(httparse::Error::TooManyHeaders) if header_count < MAX_HEADER_COUNT => {
+                header_count *= 2;
+            }
+            Err(e) => return Err(e).context("parsing HTTP message"),
+        }
+    }
+}
+
+fn start_line(raw: &[u8]) -> String {
+    let line_end = find_crlf(raw).unwrap_or_default();
+    String::from_utf8_lossy(&raw[..line_end]).into_owned()
+}
+
+/// The message with its decoded body, as if it had been sent without any
+/// transfer or content encoding.
+fn encode(start_line: &str, headers: &Headers, body: &[u8]) -> Vec<u8> {
+    let mut raw = format!("{}\r\n", start_line).into_bytes();
+    for (name, value) in headers {
+        if ["content-length", "transfer-encoding", "content-encoding"]
+            .iter()
+            .any(|framing| name.eq_ignore_ascii_case(framing))
+        {
+            continue;
+        }
+        raw.extend_from_slice(name.as_bytes());
+        raw.extend_from_slice(b": ");
+        raw.extend_from_slice(value);
+        raw.extend_from_slice(b"\r\n");
+    }
+    if !body.is_empty() {
+        raw.extend_from_slice(format!("Content-Length: {}\r\n", body.len()).as_bytes());
+    }
+    raw.extend_from_slice(b"\r\n");
+    raw.extend_from_slice(body);
+    raw
+}
+
+/// Whether `headers` say that the body is JSON.
+pub(crate) fn is_json(headers: &Headers) -> bool {
+    let content_type = match header(headers, "content-type") {
+        Some(content_type) => content_type,
+        None => return false,
+    };
+    let mime_type = content_type.split(';').next().unwrap_or_default().trim();
+    mime_type.eq_ignore_ascii_case("application/json")
+        || mime_type.to_ascii_lowercase().ends_with("+json")
+}
+
+/// The first `name` header, if it is there and is UTF-8.
+pub(crate) fn header<'a>(headers: &'a Headers, name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+        .and_then(|(_, value)| std::str::from_utf8(value).ok())
+}
+
+/// The comma-separated tokens of every `name` header, lowercased.
+fn header_tokens(headers: &Headers, name: &str) -> Vec<String> {
+    headers
+        .iter()
+        .filter(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+        .filter_map(|(_, value)| std::str::from_utf8(value).ok())
+        .flat_map(|value| value.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect()
+}
+
+fn decode_body(headers: &Headers, body: &[u8]) -> Result<Vec<u8>> {
+    let body = if header_tokens(headers, "transfer-encoding")
+        .iter()
+        .any(|token| token == "chunked")
+    {
+        dechunk(body)?
+    } else {
+        body.to_vec()
+    };
+    decode_content(headers, body)
+}
+
+fn decode_content(headers: &Headers, mut body: Vec<u8>) -> Result<Vec<u8>> {
+    // Encodings are listed in the order that they were applied.
+    for encoding in header_tokens(headers, "content-encoding").iter().rev() {
+        body =
+            decompress(encoding, &body).with_context(|| format!("decoding {} body", encoding))?;
+    }
+    Ok(body)
+}
+
+fn dechunk(mut chunked: &[u8]) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = find_crlf(chunked).context("chunk size is not terminated")?;
+        let size_line = std::str::from_utf8(&chunked[..line_end])?;
+        // Chunk extensions come after a `;`, and nobody uses them.
+        let size = size_line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .with_context(|| format!("bad chunk size {:?}", size))?;
+        chunked = &chunked[line_end + 2..];
+        if size == 0 {
+            // Anything after this is trailers, which we don't care about.
+            return Ok(body);
+        }
+        if chunked.len() < size + 2 {
+            anyhow::bail!("chunk is truncated");
+        }
+        body.extend_from_slice(&chunked[..size]);
+        chunked = &chunked[size + 2..];
+    }
+}
+
+fn find_crlf(bytes: &[u8]) -> Option<usize> {
+    bytes.windows(2).position(|window| window == b"\r\n")
+}
+
+fn decompress(encoding: &str, body: &[u8]) -> Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    match encoding {
+        "identity" => decoded.extend_from_slice(body),
+        "gzip" | "x-gzip" => {
+            flate2::read::GzDecoder::new(body).read_to_end(&mut decoded)?;
+        }
+        // `deflate` is meant to be zlib-wrapped, but some servers send raw
+        // deflate streams.
+        "deflate" => {
+            if flate2::read::ZlibDecoder::new(body)
+                .read_to_end(&mut decoded)
+                .is_err()
+            {
+                decoded.clear();
+                flate2::read::DeflateDecoder::new(body).read_to_end(&mut decoded)?;
+            }
+        }
+        "br" => {
+            brotli_decompressor::Decompressor::new(body, 4096).read_to_end(&mut decoded)?;
+        }
+        _ => anyhow::bail!("unsupported content encoding"),
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_parse_chunked_gzip_response() {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all("héllo, wörld".as_bytes()).unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        let mut raw = b"HTTP/1.1 500 Internal Server Error\r\n".to_vec();
+        for i in 0..20 {
+            raw.extend_from_slice(format!("x-header-{}: {}\r\n", i, i).as_bytes());
+        }
+        raw.extend_from_slice(b"Content-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n\r\n");
+        for chunk in gzipped.chunks(10) {
+            raw.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+            raw.extend_from_slice(chunk);
+            raw.extend_from_slice(b"\r\n");
+        }
+        raw.extend_from_slice(b"0\r\n\r\n");
+
+        let response = HttpResponse::parse(&raw).unwrap();
+        assert_eq!(response.header("X-HEADER-19"), Some("19"));
+        assert_eq!(response.text().unwrap(), "héllo, wörld");
+
+        let reparsed = HttpResponse::parse(&response.to_raw()).unwrap();
+        assert_eq!(reparsed.header("content-encoding"), None);
+        assert_eq!(reparsed.text().unwrap(), "héllo, wörld");
+    }
+
+    #[test]
+    fn test_parse_binary_response() {
+        let response =
+            HttpResponse::parse(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n\xff\x00\xfe")
+                .unwrap();
+        assert_eq!(response.bytes(), b"\xff\x00\xfe");
+        assert!(response.text().is_err());
+        assert!(!response.is_json());
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let request = HttpRequest::parse(
+            b"POST /users HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: 12\r\n\r\n{\"name\":\"x\"}",
+        )
+        .unwrap();
+        assert_eq!(request.header("content-type"), Some("application/json"));
+        assert_eq!(request.bytes(), b"{\"name\":\"x\"}");
+    }
+}
@@ -0,0 +1,273 @@
+//! Making sure that generated code has the `use` items that it needs.
+
+use crate::edit::Edit;
+use proc_macro2::LineColumn;
+use std::collections::HashSet;
+use syn::{spanned::Spanned, Item, UseTree};
+
+/// What a module can see through its `use` items.
+#[derive(Default)]
+struct Imported {
+    names: HashSet<String>,
+    /// Paths that are glob-imported, like `super` in `use super::*`.
+    globs: HashSet<String>,
+}
+
+impl Imported {
+    fn from_items(items: &[Item]) -> Self {
+        let mut imported = Imported::default();
+        for item in items {
+            if let Item::Use(item_use) = item {
+                imported.add(&[], &item_use.tree);
+            }
+        }
+        imported
+    }
+
+    fn add(&mut self, prefix: &[String], tree: &UseTree) {
+        match tree {
+            UseTree::Path(path) => {
+                let mut prefix = prefix.to_vec();
+                prefix.push(path.ident.to_string());
+                self.add(&prefix, &path.tree);
+            }
+            UseTree::Name(name) if name.ident == "self" => {
+                self.names.extend(prefix.last().cloned());
+            }
+            UseTree::Name(name) => {
+                self.names.insert(name.ident.to_string());
+            }
+            UseTree::Rename(rename) => {
+                self.names.insert(rename.rename.to_string());
+            }
+            UseTree::Glob(_) => {
+                self.globs.insert(prefix.join("::"));
+            }
+            UseTree::Group(group) => {
+                for tree in &group.items {
+                    self.add(prefix, tree);
+                }
+            }
+        }
+    }
+
+    fn has(&self, import: &str) -> bool {
+        // `use actix_web::*` brings in `actix_web::web`, but not
+        // `actix_web::web::Bytes`, and it can't rename anything.
+        let glob_covers = !import.contains(" as ")
+            && import
+                .rsplit_once("::")
+                .is_some_and(|(parent, _)| self.globs.contains(parent));
+        self.names.contains(local_name(import)) || glob_covers
+    }
+}
+
+/// Make edits that add any of `wanted` (like `"actix_web::test as atest"`)
+/// that a module doesn't already import.
+///
+/// `items` are the module's items. If the module does `use super::*` then
+/// `parent_items` are checked too. Imports are merged into an existing
+/// `use krate::{...}` if there is one, and otherwise get their own `use`
+/// after the module's last `use` (or at `fallback` if it has none).
+pub(crate) fn import_edits(
+    items: &[Item],
+    parent_items: Option<&[Item]>,
+    fallback: LineColumn,
+    wanted: &[&str],
+) -> Vec<Edit> {
+    let imported = Imported::from_items(items);
+    let parent_imported = match parent_items {
+        Some(parent_items) if imported.globs.contains("super") => {
+            Imported::from_items(parent_items)
+        }
+        _ => Imported::default(),
+    };
+
+    // Imports from the same crate go into its group together, in one edit,
+    // so that they get commas between them.
+    let mut by_crate: Vec<(&str, Vec<&str>)> = vec![];
+    for import in wanted {
+        if imported.has(import) || parent_imported.has(import) {
+            continue;
+        }
+        let (krate, _) = split_crate(import);
+        match by_crate.iter_mut().find(|(other, _)| *other == krate) {
+            Some((_, imports)) => imports.push(import),
+            None => by_crate.push((krate, vec![import])),
+        }
+    }
+
+    let mut edits = vec![];
+    for (krate, imports) in by_crate {
+        match find_use_group(items, krate) {
+            Some((end_of_group, needs_comma)) => {
+                let rests: Vec<_> = imports.iter().map(|import| split_crate(import).1).collect();
+                edits.push(Edit::insert(
+                    end_of_group,
+                    format!(
+                        "{}{}",
+                        if needs_comma { ", " } else { "" },
+                        rests.join(", ")
+                    ),
+                ));
+            }
+            None => edits.extend(imports.iter().map(|import| match last_use(items) {
+                Some(end_of_use) => Edit::insert(end_of_use, format!("\nuse {};", import)),
+                None => Edit::insert(fallback, format!("use {};\n", import)),
+            })),
+        }
+    }
+    edits
+}
+
+/// `"actix_web::web::Bytes"` -> `("actix_web", "web::Bytes")`
+fn split_crate(import: &str) -> (&str, &str) {
+    let mut parts = import.splitn(2, "::");
+    let krate = parts.next().unwrap_or_default();
+    (krate, parts.next().unwrap_or_default())
+}
+
+/// `"actix_web::test as atest"` -> `"atest"`
+fn local_name(import: &str) -> &str {
+    match import.rfind(" as ") {
+        Some(index) => &import[index + " as ".len()..],
+        None => import.rsplit("::").next().unwrap_or(import),
+    }
+}
+
+/// Find the point just before the closing brace of a `use krate::{...};`, and
+/// whether anything we add needs a comma before it.
+fn find_use_group(items: &[Item], krate: &str) -> Option<(LineColumn, bool)> {
+    items.iter().find_map(|item| match item {
+        Item::Use(item_use) => match &item_use.tree {
+            UseTree::Path(path) if path.ident == krate => match &*path.tree {
+                UseTree::Group(group) => {
+                    let end = group.brace_token.span.end();
+                    Some((
+                        LineColumn {
+                            line: end.line,
+                            column: end.column - 1,
+                        },
+                        !group.items.is_empty() && !group.items.trailing_punct(),
+                    ))
+                }
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+fn last_use(items: &[Item]) -> Option<LineColumn> {
+    items
+        .iter()
+        .rev()
+        .find(|item| matches!(item, Item::Use(_)))
+        .map(|item| item.span().end())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::edit::apply_edits;
+
+    fn add_imports(code: &str, wanted: &[&str]) -> String {
+        let syntax_tree = syn::parse_file(code).unwrap();
+        let edits = import_edits(
+            &syntax_tree.items,
+            None,
+            LineColumn { line: 1, column: 0 },
+            wanted,
+        );
+        apply_edits(code, edits).unwrap()
+    }
+
+    #[test]
+    fn test_import_edits_merges_into_group() {
+        assert_eq!(
+            add_imports(
+                "use actix_web::{get, web, App};\n",
+                &[
+                    "actix_web::post",
+                    "actix_web::web",
+                    "actix_web::HttpResponse"
+                ]
+            ),
+            "use actix_web::{get, web, App, post, HttpResponse};\n"
+        );
+    }
+
+    #[test]
+    fn test_import_edits_merges_into_group_with_trailing_comma() {
+        assert_eq!(
+            add_imports(
+                "use actix_web::{\n    get, Responder,\n};\n",
+                &["actix_web::test as atest", "actix_web::web::Bytes"]
+            ),
+            "use actix_web::{\n    get, Responder,\ntest as atest, web::Bytes};\n"
+        );
+    }
+
+    #[test]
+    fn test_import_edits_adds_use() {
+        assert_eq!(
+            add_imports(
+                "use std::collections::HashMap;\nuse actix_web::get;\n\nfn main() {}\n",
+                &["actix_web::get", "actix_web::test as atest"]
+            ),
+            "use std::collections::HashMap;\nuse actix_web::get;\nuse actix_web::test as atest;\n\nfn main() {}\n"
+        );
+        assert_eq!(
+            add_imports("fn main() {}\n", &["actix_web::Responder"]),
+            "use actix_web::Responder;\nfn main() {}\n"
+        );
+    }
+
+    #[test]
+    fn test_import_edits_through_super_glob() {
+        let syntax_tree = syn::parse_file(
+            r#"
+use actix_web::{web::Bytes, App};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test as atest;
+}
+"#,
+        )
+        .unwrap();
+        let tests_items = match &syntax_tree.items[1] {
+            Item::Mod(item_mod) => &item_mod.content.as_ref().unwrap().1,
+            _ => unreachable!(),
+        };
+        assert!(import_edits(
+            tests_items,
+            Some(&syntax_tree.items),
+            LineColumn { line: 1, column: 0 },
+            &[
+                "actix_web::test as atest",
+                "actix_web::web::Bytes",
+                "actix_web::App"
+            ],
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_import_edits_with_glob() {
+        assert_eq!(
+            add_imports(
+                "use actix_web::*;
+",
+                &[
+                    "actix_web::get",
+                    "actix_web::web::Bytes",
+                    "actix_web::test as atest"
+                ]
+            ),
+            "use actix_web::*;\nuse actix_web::web::Bytes;\nuse actix_web::test as atest;\n"
+        );
+    }
+}
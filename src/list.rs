@@ -1,20 +1,24 @@
+use crate::anonymise::Anonymiser;
+use crate::config::read_config;
+use crate::http::{Headers, HttpRequest, HttpResponse};
+use crate::redact::Redactor;
+use crate::state::State;
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Request {
     pub method: String,
     pub uri: String,
-    // TODO: write a .get_body() function
-    // let request_buf = base64::decode(&trace.request.raw)?;
-    // println!("{:?}", std::str::from_utf8(&request_buf)?);
-    // let mut request_headers = [httparse::EMPTY_HEADER; 16];
-    // let mut request = httparse::Request::new(&mut request_headers);
-    // let byte_count = request.parse(&request_buf)?;
-    // println!("{:?} => {:?}", byte_count, request);
     pub raw: String,
 }
 
 impl Request {
+    pub(crate) fn parse(&self) -> anyhow::Result<HttpRequest> {
+        HttpRequest::parse(&base64::decode(&self.raw)?)
+    }
+
     pub fn route_path(&self) -> &str {
         match self.uri.find('?') {
             Some(index) => self.uri.get(..index).unwrap(),
@@ -30,67 +34,365 @@ pub struct Response {
 }
 
 impl Response {
-    pub fn get_body(&self) -> anyhow::Result<String> {
-        let response_buf = base64::decode(&self.raw)?;
-        // println!("{:?}", std::str::from_utf8(&response_buf)?);
-        let mut response_headers = [httparse::EMPTY_HEADER; 16];
-        let mut response = httparse::Response::new(&mut response_headers);
-        let byte_count = match response.parse(&response_buf)? {
-            httparse::Status::Complete(byte_count) => byte_count,
-            httparse::Status::Partial => anyhow::bail!("response was partial"),
-        };
-        // println!("{:?} => {:?}", byte_count, response);
-        let response_body = std::str::from_utf8(&response_buf[byte_count..])?;
-        Ok(response_body.to_string())
+    pub(crate) fn parse(&self) -> anyhow::Result<HttpResponse> {
+        HttpResponse::parse(&base64::decode(&self.raw)?)
+    }
+
+    /// Whether ngrok sent this response itself, like the 502 page that it
+    /// shows when it can't reach the server.
+    pub(crate) fn is_from_ngrok(&self) -> bool {
+        match self.parse() {
+            Ok(response) => {
+                response.header("ngrok-error-code").is_some()
+                    || String::from_utf8_lossy(response.bytes()).contains("ERR_NGROK_")
+            }
+            Err(_) => false,
+        }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RequestTrace {
     pub id: String,
+    /// When the request came in, like `2020-02-27T10:57:08Z`.
+    #[serde(default)]
+    pub start: String,
     pub request: Request,
     pub response: Response,
 }
 
+impl RequestTrace {
+    /// Rewrite the uri with `uri`, and the headers and decoded bodies of
+    /// both messages with `message`.
+    pub(crate) fn rewrite(
+        mut self,
+        uri: impl Fn(&str) -> String,
+        message: impl Fn(&mut Headers, &mut Vec<u8>),
+    ) -> anyhow::Result<Self> {
+        self.request.uri = uri(&self.request.uri);
+
+        let mut request = self.request.parse()?;
+        let (request_line, headers, body) = request.parts_mut();
+        // `METHOD target HTTP/1.1`
+        let mut parts: Vec<_> = request_line.splitn(3, ' ').map(String::from).collect();
+        if let Some(target) = parts.get_mut(1) {
+            *target = uri(target);
+        }
+        *request_line = parts.join(" ");
+        message(headers, body);
+        self.request.raw = base64::encode(&request.to_raw());
+
+        let mut response = self.response.parse()?;
+        let (_, headers, body) = response.parts_mut();
+        message(headers, body);
+        self.response.raw = base64::encode(&response.to_raw());
+
+        Ok(self)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct NgrokResponse {
     requests: Vec<RequestTrace>,
 }
 
-async fn list_requests() -> Result<Vec<RequestTrace>, anyhow::Error> {
-    let resp: NgrokResponse = reqwest::get("http://127.0.0.1:4040/api/requests/http")
+const NGROK_API: &str = "http://127.0.0.1:4040/api";
+
+/// Every trace, newest first, exactly as ngrok recorded it.
+///
+/// Only use these for talking to your own server. Anything that might end
+/// up in code should come from `list_requests()`, which redacts them.
+pub(crate) async fn fetch_traces() -> Result<Vec<RequestTrace>, anyhow::Error> {
+    let resp: NgrokResponse = reqwest::get(&format!("{}/requests/http", NGROK_API))
         .await?
         .json()
         .await?;
     Ok(resp.requests)
 }
 
-async fn traces_for_code(code: u32) -> Result<impl Iterator<Item = RequestTrace>, anyhow::Error> {
-    Ok(list_requests()
+/// The trace with this `id`, exactly as ngrok recorded it.
+pub(crate) async fn fetch_trace(id: &str) -> Result<RequestTrace, anyhow::Error> {
+    let trace = reqwest::get(&format!("{}/requests/http/{}", NGROK_API, id))
         .await?
-        .into_iter()
-        .filter(move |t| t.response.status_code == code))
+        .error_for_status()
+        .with_context(|| format!("fetching trace {}", id))?
+        .json()
+        .await?;
+    Ok(trace)
+}
+
+#[derive(Debug, Serialize)]
+struct ReplayRequest<'a> {
+    id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tunnel_name: Option<&'a str>,
+}
+
+/// Ask ngrok to send the request from trace `id` through a tunnel again.
+/// It records the outcome as a new trace.
+pub(crate) async fn ngrok_replay(id: &str, tunnel_name: Option<&str>) -> Result<(), anyhow::Error> {
+    reqwest::Client::new()
+        .post(&format!("{}/requests/http", NGROK_API))
+        .json(&ReplayRequest { id, tunnel_name })
+        .send()
+        .await?
+        .error_for_status()
+        .with_context(|| format!("asking ngrok to replay trace {}", id))?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct TunnelsResponse {
+    tunnels: Vec<Tunnel>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Tunnel {
+    proto: String,
+    config: TunnelConfig,
+}
+
+#[derive(Debug, Deserialize)]
+struct TunnelConfig {
+    addr: String,
 }
 
-pub async fn latest_trace_for_code(code: u32) -> Result<RequestTrace, anyhow::Error> {
-    traces_for_code(code)
+/// Where ngrok forwards http requests to, like `http://localhost:8080`.
+pub(crate) async fn tunnel_address() -> Result<String, anyhow::Error> {
+    let resp: TunnelsResponse = reqwest::get(&format!("{}/tunnels", NGROK_API))
         .await?
+        .json()
+        .await?;
+    resp.tunnels
+        .into_iter()
+        .find(|tunnel| tunnel.proto.starts_with("http"))
+        .map(|tunnel| tunnel.config.addr)
+        .context("ngrok doesn't have any http tunnels")
+}
+
+/// Every trace, newest first, with secrets and personal data taken out.
+pub(crate) async fn list_requests() -> Result<Vec<RequestTrace>, anyhow::Error> {
+    let requests = fetch_traces().await?;
+    let config = read_config()?;
+    let redactor = Redactor::from_config(&config)?;
+    let anonymiser = Anonymiser::from_config(&config)?;
+    Ok(requests
         .into_iter()
-        .next()
-        .ok_or(anyhow::anyhow!("no traces found for code {}", code))
+        .filter_map(|trace| {
+            let id = trace.id.clone();
+            match redactor
+                .redact_trace(trace)
+                .and_then(|trace| anonymiser.anonymise_trace(trace))
+            {
+                Ok(trace) => Some(trace),
+                Err(e) => {
+                    // Better to lose the trace than to leak what's in it.
+                    eprintln!(
+                        "skipping trace {}, because it can't be redacted: {:#}",
+                        id, e
+                    );
+                    None
+                }
+            }
+        })
+        .collect())
 }
 
-async fn list_routes_for_code(code: u32) -> Result<impl Iterator<Item = String>, anyhow::Error> {
-    Ok(traces_for_code(code)
+/// Which response statuses to look at, like `500`, `5xx` or `500-504`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StatusFilter {
+    min: u32,
+    max: u32,
+}
+
+impl StatusFilter {
+    pub fn code(code: u32) -> Self {
+        StatusFilter {
+            min: code,
+            max: code,
+        }
+    }
+
+    pub fn matches(&self, code: u32) -> bool {
+        self.min <= code && code <= self.max
+    }
+
+    /// Whether the server sent `trace` a response with a matching status.
+    /// ngrok's own error pages don't count.
+    pub(crate) fn matches_trace(&self, trace: &RequestTrace) -> bool {
+        self.matches(trace.response.status_code) && !trace.response.is_from_ngrok()
+    }
+}
+
+impl std::str::FromStr for StatusFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_code = |code: &str| -> Result<u32, anyhow::Error> {
+            match code.parse() {
+                Ok(code) if (100..=599).contains(&code) => Ok(code),
+                _ => anyhow::bail!("{:?} is not an HTTP status code", code),
+            }
+        };
+        let filter = if let Some(class) = s.strip_suffix("xx") {
+            let min = parse_code(&format!("{}00", class))?;
+            StatusFilter { min, max: min + 99 }
+        } else if let Some((min, max)) = s.split_once('-') {
+            StatusFilter {
+                min: parse_code(min)?,
+                max: parse_code(max)?,
+            }
+        } else {
+            StatusFilter::code(parse_code(s)?)
+        };
+        if filter.min > filter.max {
+            anyhow::bail!("{:?} is an empty range", s);
+        }
+        Ok(filter)
+    }
+}
+
+impl std::fmt::Display for StatusFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.min == self.max {
+            write!(f, "{}", self.min)
+        } else if self.min.is_multiple_of(100) && self.max == self.min + 99 {
+            write!(f, "{}xx", self.min / 100)
+        } else {
+            write!(f, "{}-{}", self.min, self.max)
+        }
+    }
+}
+
+/// Traces with a matching status, newest first.
+pub async fn traces_matching(
+    status: StatusFilter,
+) -> Result<impl Iterator<Item = RequestTrace>, anyhow::Error> {
+    Ok(list_requests()
         .await?
         .into_iter()
-        .map(|t| t.request.uri))
+        .filter(move |t| status.matches_trace(t)))
 }
 
-pub async fn list_404() -> Result<impl Iterator<Item = String>, anyhow::Error> {
-    list_routes_for_code(404).await
+/// The newest trace with a matching status that hasn't been turned into code
+/// yet.
+pub async fn latest_trace_matching(
+    status: StatusFilter,
+    state: &State,
+) -> Result<RequestTrace, anyhow::Error> {
+    traces_matching(status)
+        .await?
+        .find(|t| !state.is_handled(&t.id))
+        .ok_or(anyhow::anyhow!("no new traces found for status {}", status))
 }
 
-pub async fn list_500() -> Result<impl Iterator<Item = String>, anyhow::Error> {
-    list_routes_for_code(500).await
+#[derive(StructOpt, Debug)]
+pub struct ListOptions {
+    /// leave out requests that have already been turned into code
+    #[structopt(long)]
+    hide_handled: bool,
+}
+
+async fn list_routes_for_code(
+    code: u32,
+    options: &ListOptions,
+) -> Result<impl Iterator<Item = String>, anyhow::Error> {
+    let state = State::load()?;
+    let hide_handled = options.hide_handled;
+    Ok(traces_matching(StatusFilter::code(code))
+        .await?
+        .filter_map(move |t| match state.handled(&t.id) {
+            Some(_) if hide_handled => None,
+            Some(handled) => Some(format!(
+                "{} (handled by {}: {})",
+                t.request.uri, handled.command, handled.function
+            )),
+            None => Some(t.request.uri),
+        }))
+}
+
+pub async fn list_404(
+    options: &ListOptions,
+) -> Result<impl Iterator<Item = String>, anyhow::Error> {
+    list_routes_for_code(404, options).await
+}
+
+pub async fn list_500(
+    options: &ListOptions,
+) -> Result<impl Iterator<Item = String>, anyhow::Error> {
+    list_routes_for_code(500, options).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_from_ngrok() {
+        let response = |raw: &str| Response {
+            status_code: 502,
+            raw: base64::encode(raw),
+        };
+        assert!(response(
+            "HTTP/1.1 502 Bad Gateway\r\nNgrok-Error-Code: ERR_NGROK_8012\r\nContent-Length: 0\r\n\r\n"
+        )
+        .is_from_ngrok());
+        assert!(response(
+            "HTTP/1.1 502 Bad Gateway\r\nContent-Length: 42\r\n\r\n<p>failed to reach upstream</p>ERR_NGROK_8012"
+        )
+        .is_from_ngrok());
+        assert!(
+            !response("HTTP/1.1 502 Bad Gateway\r\nContent-Length: 11\r\n\r\nBad Gateway")
+                .is_from_ngrok()
+        );
+    }
+
+    #[test]
+    fn test_status_filter_matches_trace() {
+        let trace = |raw: &str| RequestTrace {
+            id: "1".into(),
+            start: String::new(),
+            request: Request {
+                method: "GET".into(),
+                uri: "/".into(),
+                raw: String::new(),
+            },
+            response: Response {
+                status_code: 502,
+                raw: base64::encode(raw),
+            },
+        };
+        let status: StatusFilter = "5xx".parse().unwrap();
+        assert!(status.matches_trace(&trace(
+            "HTTP/1.1 502 Bad Gateway\r\nContent-Length: 11\r\n\r\nBad Gateway"
+        )));
+        assert!(!status.matches_trace(&trace(
+            "HTTP/1.1 502 Bad Gateway\r\nNgrok-Error-Code: ERR_NGROK_8012\r\nContent-Length: 0\r\n\r\n"
+        )));
+        assert!(!StatusFilter::code(404).matches_trace(&trace(
+            "HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n"
+        )));
+    }
+
+    #[test]
+    fn test_status_filter() {
+        for (filter, (min, max)) in &[
+            ("503", (503, 503)),
+            ("5xx", (500, 599)),
+            ("400-404", (400, 404)),
+        ] {
+            let parsed: StatusFilter = filter.parse().unwrap();
+            assert_eq!(
+                parsed,
+                StatusFilter {
+                    min: *min,
+                    max: *max
+                }
+            );
+            assert_eq!(&parsed.to_string(), filter);
+        }
+        assert!("600".parse::<StatusFilter>().is_err());
+        assert!("504-502".parse::<StatusFilter>().is_err());
+        assert!("5x".parse::<StatusFilter>().is_err());
+    }
 }
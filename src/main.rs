@@ -1,10 +1,27 @@
+mod anonymise;
+mod config;
+mod edit;
+mod hash;
+mod http;
+mod imports;
 mod list;
+mod manifest;
 mod new;
+mod output;
 mod parse_code;
+mod redact;
+mod replay;
 mod run_ngrok;
+mod rustfmt;
+mod state;
+mod watch;
 
-use crate::list::{list_404, list_500};
+use crate::list::StatusFilter;
+use crate::list::{list_404, list_500, ListOptions};
+use crate::new::{GenerateOptions, TestOptions};
+use crate::replay::{replay, replay_all, replay_latest, ReplayAllOptions, ReplayOptions};
 use crate::run_ngrok::run_ngrok;
+use crate::watch::{watch, WatchOptions};
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
@@ -44,13 +61,19 @@ enum Ngrok {
     ///
     /// Requests that receive 404 responses are typically ones that you haven't
     /// written handlers for yet. Use `new-handler` to define routes for these.
-    _404s,
+    _404s {
+        #[structopt(flatten)]
+        options: ListOptions,
+    },
 
     /// list requests for turning into regression tests
     ///
     /// Requests that receive 500 responses are typically ones that caused your
     /// code to error out. Use `new-test` to make regression tests for these.
-    _500s,
+    _500s {
+        #[structopt(flatten)]
+        options: ListOptions,
+    },
 
     /// make a new route handler from the latest 404 error
     ///
@@ -59,29 +82,87 @@ enum Ngrok {
     /// it doesn't, please file a bug). You can then use your standard
     /// test-driven development workflow to make the request handler do what
     /// you want.
-    NewHandler,
+    NewHandler {
+        #[structopt(flatten)]
+        options: GenerateOptions,
+    },
 
-    /// make a regression test from the latest 500 error
+    /// make a regression test from the latest 5xx error
     ///
     /// Makes an integration test that includes the request's payload.
     /// The integration test should initially fail. You can then use your
     /// standard test-driven development workflow to fix the request handler.
-    NewTest,
+    NewTest {
+        #[structopt(flatten)]
+        options: GenerateOptions,
+        #[structopt(flatten)]
+        test_options: TestOptions,
+    },
 
-    /// replay the latest 404 error (TODO)
+    /// make a golden test from the latest successful response
+    ///
+    /// Makes an integration test that checks the status, key headers and body
+    /// of the response. It should initially pass. Use it to pin down how an
+    /// endpoint behaves before you refactor it.
+    NewGoldenTest {
+        #[structopt(flatten)]
+        options: GenerateOptions,
+        #[structopt(flatten)]
+        test_options: TestOptions,
+    },
+
+    /// replay a trace against your server, and diff the response
+    ///
+    /// Sends the traced request again, and compares the status, headers and
+    /// body of the response with the recorded one. Exits non-zero if they
+    /// differ, so you can use it in scripts.
+    Replay {
+        /// the id of the trace, as shown in the ngrok web interface
+        #[structopt(long)]
+        id: String,
+        #[structopt(flatten)]
+        options: ReplayOptions,
+    },
+
+    /// replay every trace against your server, as a smoke test
+    ///
+    /// Sends the traced requests again, oldest first, and reports which
+    /// endpoints changed their status, headers or body. Exits non-zero if any
+    /// of them did.
+    ReplayAll {
+        #[structopt(flatten)]
+        options: ReplayAllOptions,
+    },
+
+    /// replay the latest 404 error
     ///
     /// Use this to smoke-test the endpoint that you just wrote with
     /// `new-handler`.
     #[allow(non_camel_case_types)]
-    Replay_404,
+    Replay_404 {
+        #[structopt(flatten)]
+        options: ReplayOptions,
+    },
 
-    /// replay the latest 500 error (TODO)
+    /// replay the latest 500 error
     ///
     /// Use this to smoke-test the endpoint that you just wrote a regression
     /// test for, with `new-test`. We've all written our share of tests that
     /// are completely disconnected from reality. You are forgiven.
     #[allow(non_camel_case_types)]
-    Replay_500,
+    Replay_500 {
+        #[structopt(flatten)]
+        options: ReplayOptions,
+    },
+
+    /// watch for new 404s and 5xxs, and suggest what to do about them
+    ///
+    /// Prints each new 404 or 5xx response as it comes in. With `--auto`, it
+    /// runs `new-handler` or `new-test` for it too, once per route.
+    Watch {
+        #[structopt(flatten)]
+        options: WatchOptions,
+    },
 
     /// The rest are passed to ngrok, for convenience.
     #[structopt(external_subcommand)]
@@ -97,12 +178,22 @@ async fn main() -> Result<(), anyhow::Error> {
             run_ngrok(args)?;
         }
         Ngrok::Develop => todo!(),
-        Ngrok::_404s => list_404().await?.for_each(|r| println!("{}", r)),
-        Ngrok::_500s => list_500().await?.for_each(|r| println!("{}", r)),
-        Ngrok::NewHandler => crate::new::new_handler().await?,
-        Ngrok::NewTest => crate::new::new_test().await?,
-        Ngrok::Replay_404 => todo!(),
-        Ngrok::Replay_500 => todo!(),
+        Ngrok::_404s { options } => list_404(&options).await?.for_each(|r| println!("{}", r)),
+        Ngrok::_500s { options } => list_500(&options).await?.for_each(|r| println!("{}", r)),
+        Ngrok::NewHandler { options } => crate::new::new_handler(options).await?,
+        Ngrok::NewTest {
+            options,
+            test_options,
+        } => crate::new::new_test(options, test_options).await?,
+        Ngrok::NewGoldenTest {
+            options,
+            test_options,
+        } => crate::new::new_golden_test(options, test_options).await?,
+        Ngrok::Watch { options } => watch(options).await?,
+        Ngrok::Replay { id, options } => replay(&id, &options).await?,
+        Ngrok::ReplayAll { options } => replay_all(&options).await?,
+        Ngrok::Replay_404 { options } => replay_latest(StatusFilter::code(404), &options).await?,
+        Ngrok::Replay_500 { options } => replay_latest(StatusFilter::code(500), &options).await?,
     }
     Ok(())
 }
@@ -0,0 +1,316 @@
+//! Reading the target project's `Cargo.toml`.
+
+use crate::output::OutputOptions;
+use anyhow::{Context, Result};
+use toml_edit::{value, Array, DocumentMut, InlineTable, Item, Table};
+
+pub(crate) const MANIFEST_PATH: &str = "Cargo.toml";
+
+pub(crate) fn read_manifest(file_path: &str) -> Result<DocumentMut> {
+    let content =
+        std::fs::read_to_string(file_path).with_context(|| format!("reading {:?}", file_path))?;
+    content
+        .parse()
+        .with_context(|| format!("parsing {:?}", file_path))
+}
+
+/// The `package.edition` of the manifest, if it sets one.
+pub(crate) fn edition(manifest: &DocumentMut) -> Option<&str> {
+    manifest.get("package")?.get("edition")?.as_str()
+}
+
+/// The `package.name` of the manifest, as it is spelled in Rust code.
+pub(crate) fn crate_name(manifest: &DocumentMut) -> Option<String> {
+    let name = manifest.get("package")?.get("name")?.as_str()?;
+    Some(name.replace('-', "_"))
+}
+
+/// The name that integration tests use for the library target.
+pub(crate) fn library_name(manifest: &DocumentMut) -> Option<String> {
+    match manifest
+        .get("lib")
+        .and_then(|lib| lib.get("name")?.as_str())
+    {
+        Some(name) => Some(name.to_string()),
+        None => crate_name(manifest),
+    }
+}
+
+/// A crate that generated code needs.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Dependency {
+    pub(crate) name: &'static str,
+    pub(crate) version: String,
+    /// Whether it's only needed by tests.
+    pub(crate) dev: bool,
+    pub(crate) features: &'static [&'static str],
+}
+
+pub(crate) const LOCKFILE_NAME: &str = "Cargo.lock";
+
+/// The actix-web versions that we know how to generate code for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ActixVersion {
+    V1,
+    V2,
+    V3,
+    V4,
+}
+
+impl ActixVersion {
+    pub(crate) fn from_major(major: u64) -> Self {
+        match major {
+            0 | 1 => ActixVersion::V1,
+            2 => ActixVersion::V2,
+            3 => ActixVersion::V3,
+            _ => ActixVersion::V4,
+        }
+    }
+}
+
+/// Work out which version of actix-web the project uses, from `Cargo.lock`
+/// if we can find one (it might be in a workspace root above us), or from
+/// what `Cargo.toml` asks for.
+pub(crate) fn detect_actix_version() -> Result<ActixVersion> {
+    let lockfile = std::env::current_dir()?
+        .ancestors()
+        .map(|dir| dir.join(LOCKFILE_NAME))
+        .find(|path| path.exists());
+    if let Some(lockfile) = lockfile {
+        let lockfile = read_manifest(&lockfile.to_string_lossy())?;
+        if let Some(major) = locked_major_version(&lockfile, "actix-web") {
+            return Ok(ActixVersion::from_major(major));
+        }
+    }
+    let manifest = read_manifest(MANIFEST_PATH)?;
+    requested_major_version(&manifest, "actix-web")
+        .map(ActixVersion::from_major)
+        .with_context(|| {
+            format!(
+                "could not find actix-web in {} or {}. Try --actix-version.",
+                LOCKFILE_NAME, MANIFEST_PATH
+            )
+        })
+}
+
+/// The newest major version of `name` in the lockfile.
+fn locked_major_version(lockfile: &DocumentMut, name: &str) -> Option<u64> {
+    lockfile
+        .get("package")?
+        .as_array_of_tables()?
+        .iter()
+        .filter(|package| package.get("name").and_then(Item::as_str) == Some(name))
+        .filter_map(|package| major_version(package.get("version")?.as_str()?))
+        .max()
+}
+
+/// The major version of `name` that the manifest asks for.
+fn requested_major_version(manifest: &DocumentMut, name: &str) -> Option<u64> {
+    let dependency = manifest.get("dependencies")?.get(name)?;
+    let requirement = match dependency.as_str() {
+        Some(requirement) => requirement,
+        None => dependency.get("version")?.as_str()?,
+    };
+    major_version(requirement)
+}
+
+/// `"^3.3"` -> `3`
+fn major_version(version: &str) -> Option<u64> {
+    version
+        .trim_start_matches(|c: char| !c.is_ascii_digit())
+        .split('.')
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// The dependencies that generated tests need, for a project that uses the
+/// given version of actix-web.
+pub(crate) fn test_dependencies(actix_version: ActixVersion) -> Vec<Dependency> {
+    match actix_version {
+        // Tests are synchronous in 1.x, and use `#[actix_web::test]` in 4.x.
+        ActixVersion::V1 | ActixVersion::V4 => vec![],
+        ActixVersion::V2 | ActixVersion::V3 => vec![Dependency {
+            name: "actix-rt",
+            version: "1".into(),
+            dev: true,
+            features: &[],
+        }],
+    }
+}
+
+/// For generated tests that compare JSON.
+pub(crate) fn serde_json_dependency() -> Dependency {
+    Dependency {
+        name: "serde_json",
+        version: "1".into(),
+        dev: true,
+        features: &[],
+    }
+}
+
+/// For snapshot tests.
+pub(crate) fn insta_dependency() -> Dependency {
+    Dependency {
+        name: "insta",
+        version: "1".into(),
+        dev: true,
+        features: &["json"],
+    }
+}
+
+fn has_dependency(manifest: &DocumentMut, dependency: &Dependency) -> bool {
+    let tables: &[&str] = if dependency.dev {
+        &["dependencies", "dev-dependencies"]
+    } else {
+        &["dependencies"]
+    };
+    tables.iter().any(|table| {
+        manifest
+            .get(table)
+            .and_then(|table| table.get(dependency.name))
+            .is_some()
+    })
+}
+
+/// Add the dependencies that aren't already in the manifest, and return
+/// their names.
+fn add_dependencies(manifest: &mut DocumentMut, dependencies: &[Dependency]) -> Vec<&'static str> {
+    let mut added = Vec::new();
+    for dependency in dependencies {
+        if has_dependency(manifest, dependency) {
+            continue;
+        }
+        let table = if dependency.dev {
+            "dev-dependencies"
+        } else {
+            "dependencies"
+        };
+        let table = manifest
+            .entry(table)
+            .or_insert_with(|| Item::Table(Table::new()));
+        table[dependency.name] = if dependency.features.is_empty() {
+            value(dependency.version.as_str())
+        } else {
+            let mut spec = InlineTable::new();
+            spec.insert("version", dependency.version.as_str().into());
+            spec.insert(
+                "features",
+                dependency
+                    .features
+                    .iter()
+                    .copied()
+                    .collect::<Array>()
+                    .into(),
+            );
+            value(spec)
+        };
+        added.push(dependency.name);
+    }
+    added
+}
+
+/// Make sure that the manifest has the dependencies that generated code
+/// needs. If `add` is false then we only warn about missing ones.
+pub(crate) fn ensure_dependencies(
+    output: &OutputOptions,
+    add: bool,
+    dependencies: &[Dependency],
+) -> Result<()> {
+    let mut manifest = read_manifest(MANIFEST_PATH)?;
+    let old = manifest.to_string();
+    let added = add_dependencies(&mut manifest, dependencies);
+    if added.is_empty() {
+        return Ok(());
+    }
+    if !add {
+        eprintln!(
+            "warning: the generated code needs {} in {:?}. Use --add-dependencies to add them.",
+            added.join(", "),
+            MANIFEST_PATH
+        );
+        return Ok(());
+    }
+    output.write_file(MANIFEST_PATH, &old, &manifest.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edition() {
+        let manifest: DocumentMut = r#"
+[package]
+name = "template_yarte"
+edition = "2018"
+
+[dependencies]
+actix-web = "2.0"
+"#
+        .parse()
+        .unwrap();
+        assert_eq!(edition(&manifest), Some("2018"));
+        assert_eq!(crate_name(&manifest), Some("template_yarte".into()));
+        assert_eq!(requested_major_version(&manifest, "actix-web"), Some(2));
+    }
+
+    #[test]
+    fn test_add_dependencies() {
+        let mut manifest: DocumentMut = r#"[package]
+name = "template_yarte"
+
+[dependencies]
+# The web framework.
+actix-web = { version = "^3.3", features = ["rustls"] }
+actix-rt = "1"
+"#
+        .parse()
+        .unwrap();
+        assert_eq!(requested_major_version(&manifest, "actix-web"), Some(3));
+
+        let mut dependencies = test_dependencies(ActixVersion::V3);
+        dependencies.push(serde_json_dependency());
+        dependencies.push(insta_dependency());
+        assert_eq!(
+            add_dependencies(&mut manifest, &dependencies),
+            vec!["serde_json", "insta"]
+        );
+        assert_eq!(
+            manifest.to_string(),
+            r#"[package]
+name = "template_yarte"
+
+[dependencies]
+# The web framework.
+actix-web = { version = "^3.3", features = ["rustls"] }
+actix-rt = "1"
+
+[dev-dependencies]
+serde_json = "1"
+insta = { version = "1", features = ["json"] }
+"#
+        );
+    }
+
+    #[test]
+    fn test_locked_major_version() {
+        let lockfile: DocumentMut = r#"
+[[package]]
+name = "actix-web"
+version = "3.3.2"
+
+[[package]]
+name = "actix-web"
+version = "4.0.1"
+
+[[package]]
+name = "actix-rt"
+version = "2.6.0"
+"#
+        .parse()
+        .unwrap();
+        assert_eq!(locked_major_version(&lockfile, "actix-web"), Some(4));
+        assert_eq!(locked_major_version(&lockfile, "serde"), None);
+    }
+}
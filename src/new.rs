@@ -1,119 +1,1074 @@
-use crate::list::RequestTrace;
+use crate::anonymise::has_unexplained_fakes;
+use crate::edit::{apply_edits, Edit};
+use crate::hash::fnv1a;
+use crate::http::HttpResponse;
+use crate::imports::import_edits;
+use crate::list::{latest_trace_matching, traces_matching, RequestTrace, StatusFilter};
+use crate::manifest::{
+    crate_name, detect_actix_version, ensure_dependencies, insta_dependency, library_name,
+    read_manifest, serde_json_dependency, test_dependencies, ActixVersion, Dependency,
+    MANIFEST_PATH,
+};
+use crate::output::OutputOptions;
 use crate::parse_code::{
-    find_handler_attr, find_handler_function_name, find_service_registration, find_test_attr,
+    end_of_file, find_fn, find_handler_fns, find_handler_function_name, find_service_registration,
+    find_test_attr, find_test_module, first_item, parse_file, RegistrationKind,
 };
+use crate::redact::is_redacted;
+use crate::state::State;
 use anyhow::{Context, Result};
-use proc_macro2::LineColumn;
+use std::collections::HashMap;
+use structopt::StructOpt;
 
 fn read_file(file_path: &str) -> Result<String> {
     std::fs::read_to_string(file_path).with_context(|| format!("reading {:?}", file_path))
 }
 
-fn write_file(file_path: &str, content: &str, edits: Vec<(LineColumn, String)>) -> Result<()> {
-    let mut lines = content.lines().map(|s| format!("{}\n", s)).collect();
+// Options that all of the code-generating commands take.
+//
+// (Not a doc comment, because structopt would use it as the about text of
+// every subcommand that flattens this.)
+#[derive(StructOpt)]
+pub struct GenerateOptions {
+    #[structopt(flatten)]
+    output: OutputOptions,
+
+    /// add any crates that the generated code needs to Cargo.toml
+    #[structopt(long)]
+    add_dependencies: bool,
+
+    /// the major version of actix-web to generate code for
+    ///
+    /// By default, this comes from Cargo.lock (or Cargo.toml).
+    #[structopt(long)]
+    actix_version: Option<u64>,
+}
 
-    for (location, code) in edits {
-        insert(&mut lines, location, &code);
+impl GenerateOptions {
+    fn actix_version(&self) -> Result<ActixVersion> {
+        match self.actix_version {
+            Some(major) => Ok(ActixVersion::from_major(major)),
+            None => detect_actix_version(),
+        }
     }
-    std::fs::write(&file_path, lines.concat()).context(format!("writing {:?}", file_path))?;
+}
+
+// Options for the commands that generate tests from traces.
+#[derive(StructOpt, Default)]
+pub struct TestOptions {
+    /// make a test for the latest response with this status (e.g. `503`,
+    /// `4xx` or `500-504`)
+    ///
+    /// Defaults to 5xx for new-test, and 2xx for new-golden-test.
+    #[structopt(long)]
+    status: Option<StatusFilter>,
 
-    Ok(())
+    /// leave these fields out when comparing JSON responses (e.g. `id,created_at`)
+    #[structopt(long, use_delimiter = true)]
+    ignore_field: Vec<String>,
+
+    /// compare the response body with an insta snapshot, rather than inline
+    ///
+    /// The snapshot is seeded from the traced response. Review changes to it
+    /// with `cargo insta review`.
+    #[structopt(long)]
+    snapshot: bool,
+
+    /// write the test to its own file in tests/ (per-test), or to a file
+    /// for its handler (per-route), rather than into src/main.rs
+    ///
+    /// Integration tests reach the handlers through the library target, so
+    /// they need to be `pub` in src/lib.rs.
+    #[structopt(long, possible_values = &["per-test", "per-route"])]
+    integration: Option<IntegrationLayout>,
+
+    /// make a test for every distinct request (by method, route and body),
+    /// rather than just the latest one
+    ///
+    /// Tests that already exist are skipped.
+    #[structopt(long)]
+    all: bool,
 }
 
-fn insert(lines: &mut Vec<String>, location: LineColumn, code: &str) {
-    lines
-        .get_mut(location.line - 1)
-        .unwrap()
-        .insert_str(location.column, &code);
+/// Make sure that `Cargo.toml` has what the generated tests need.
+fn ensure_test_dependencies(
+    options: &GenerateOptions,
+    actix_version: ActixVersion,
+    extra_dependencies: Vec<Dependency>,
+) -> Result<()> {
+    let mut dependencies = test_dependencies(actix_version);
+    dependencies.extend(extra_dependencies);
+    ensure_dependencies(&options.output, options.add_dependencies, &dependencies)
 }
 
-pub async fn new_handler() -> Result<()> {
-    let trace = crate::list::latest_trace_for_code(404).await?;
+pub async fn new_handler(options: GenerateOptions) -> Result<()> {
+    let mut state = State::load()?;
+    let trace = latest_trace_matching(StatusFilter::code(404), &state).await?;
+    new_handler_for_trace(&options, trace, &mut state)
+}
+
+/// Make a handler for `trace`, and remember that it's been handled.
+pub(crate) fn new_handler_for_trace(
+    options: &GenerateOptions,
+    trace: RequestTrace,
+    state: &mut State,
+) -> Result<()> {
+    let trace_id = trace.id.clone();
+    let handler_name = handler_name(&trace.request.uri);
     let file_path = "src/main.rs";
     let content = read_file(file_path)?;
 
-    let edits = edits_for_new_handler(trace, &content)?;
+    let syntax_tree = parse_file(file_path, &content)?;
+    let actix_version = options.actix_version()?;
+
+    if let Some(existing) = existing_handler(&syntax_tree, &trace, &handler_name)? {
+        eprintln!(
+            "skipping {} {}, because {} already handles it",
+            trace.request.method, trace.request.uri, existing
+        );
+        state.record(&trace_id, "new-handler", &existing);
+        return state.save(&options.output);
+    }
+    let edits = edits_for_new_handler(actix_version, trace, &syntax_tree)?;
+
+    options
+        .output
+        .write_rust_file(file_path, &content, &apply_edits(&content, edits)?)?;
+    ensure_test_dependencies(options, actix_version, vec![])?;
+    state.record(&trace_id, "new-handler", &handler_name);
+    state.save(&options.output)
+}
+
+/// `"/favicon.ico"` -> `"favicon_ico"`
+fn handler_name(uri: &str) -> String {
+    let safe_name = uri.replace(|c: char| !c.is_ascii_lowercase(), "_");
+    safe_name.trim_start_matches('_').to_string()
+}
+
+/// The handler that already serves `trace`'s route, if there is one.
+///
+/// This can happen if the server hasn't been restarted since the handler was
+/// made. It's an error if the route is new but `handler_name` is taken, by a
+/// handler for the same path with another method, say.
+fn existing_handler(
+    syntax_tree: &syn::File,
+    trace: &RequestTrace,
+    handler_name: &str,
+) -> Result<Option<String>> {
+    let scope = find_service_registration(syntax_tree, trace.request.route_path())
+        .ok()
+        .and_then(|registration| registration.scope);
+    let route_path = scoped_route(scope.as_deref(), trace.request.route_path());
+    if let Ok(existing) = find_handler_function_name(syntax_tree, &trace.request.method, route_path)
+    {
+        return Ok(Some(existing));
+    }
+    if find_fn(syntax_tree, handler_name).is_some() {
+        anyhow::bail!(
+            "there's already a fn called {}, so can't make a handler for {} {}",
+            handler_name,
+            trace.request.method,
+            trace.request.uri
+        );
+    }
+    Ok(None)
+}
+
+fn edits_for_new_handler(
+    actix_version: ActixVersion,
+    trace: RequestTrace,
+    syntax_tree: &syn::File,
+) -> Result<Vec<Edit>> {
+    let route_path = trace.request.route_path();
+    let registration = find_service_registration(syntax_tree, route_path)?;
+
+    let handler_name = &handler_name(&trace.request.uri);
 
-    write_file(file_path, &content, edits)
+    let scope = registration.scope.as_deref();
+    let service = scoped_service(scope, handler_name);
+
+    let handler_fn =
+        format_handler_fn(actix_version, handler_name, scoped_route(scope, route_path));
+    let integration_test =
+        format_integration_test(actix_version, handler_name, &service, &trace.request.uri);
+    let service_registration = format_service_registration(&registration.kind, handler_name);
+
+    let mut edits = import_edits(
+        &syntax_tree.items,
+        None,
+        first_item(syntax_tree)?,
+        HANDLER_IMPORTS,
+    );
+    edits.push(handler_edit(syntax_tree, handler_fn)?);
+    edits.extend(test_edits(actix_version, syntax_tree, integration_test)?);
+    edits.push(Edit::insert(registration.at, service_registration));
+    Ok(edits)
 }
 
-fn edits_for_new_handler(trace: RequestTrace, content: &str) -> Result<Vec<(LineColumn, String)>> {
-    let existing_handler = find_handler_attr(&content)?;
-    let existing_test = find_test_attr(&content)?;
-    let existing_service_registration = find_service_registration(&content)?;
+/// Make `route_path` relative to `scope`, if we're registering into one.
+fn scoped_route<'a>(scope: Option<&str>, route_path: &'a str) -> &'a str {
+    match scope {
+        Some(scope) => &route_path[scope.trim_end_matches('/').len()..],
+        None => route_path,
+    }
+}
 
-    let safe_name = trace
-        .request
-        .uri
-        .replace(|c: char| !c.is_ascii_lowercase(), "_");
-    let handler_name = safe_name.trim_start_matches('_');
+/// The service expression that tests should put into their `App`.
+fn scoped_service(scope: Option<&str>, handler_name: &str) -> String {
+    match scope {
+        Some(scope) => format!(
+            "actix_web::web::scope({:?}).service({})",
+            scope, handler_name
+        ),
+        None => handler_name.to_string(),
+    }
+}
 
-    let handler_fn = format_handler_fn(&handler_name, &trace.request.route_path());
-    let integration_test = format_integration_test(&handler_name, &trace.request.uri);
-    let service_registration = format!(".service({})", handler_name);
+fn format_service_registration(kind: &RegistrationKind, handler_name: &str) -> String {
+    match kind {
+        RegistrationKind::Builder => format!(".service({})", handler_name),
+        RegistrationKind::Config {
+            cfg,
+            needs_semicolon,
+        } => format!(
+            "{semicolon}\n{cfg}.service({handler_name});\n",
+            semicolon = if *needs_semicolon { ";" } else { "" },
+            cfg = cfg,
+            handler_name = handler_name,
+        ),
+    }
+}
 
-    Ok(vec![
-        (existing_handler.start, handler_fn),
-        (existing_test.start, integration_test),
-        (existing_service_registration.end, service_registration),
-    ])
+/// The imports that `format_handler_fn()` needs.
+const HANDLER_IMPORTS: &[&str] = &["actix_web::get", "actix_web::Responder"];
+
+/// Put new handlers after the last existing handler, or before `main()` if
+/// there aren't any yet.
+fn handler_edit(syntax_tree: &syn::File, handler_fn: String) -> Result<Edit> {
+    if let Some(last_handler) = find_handler_fns(syntax_tree).last() {
+        return Ok(Edit::insert(last_handler.end, handler_fn));
+    }
+    if let Some(main) = find_fn(syntax_tree, "main") {
+        return Ok(Edit::insert(main.start, handler_fn));
+    }
+    Ok(Edit::insert(end_of_file(syntax_tree)?, handler_fn))
 }
 
-pub async fn new_test() -> Result<()> {
-    let trace = crate::list::latest_trace_for_code(500).await?;
+/// The imports that generated tests need.
+fn test_imports(actix_version: ActixVersion) -> &'static [&'static str] {
+    match actix_version {
+        ActixVersion::V4 => &[
+            "actix_web::body",
+            "actix_web::test as atest",
+            "actix_web::web::Bytes",
+            "actix_web::App",
+        ],
+        _ => &[
+            "actix_web::test as atest",
+            "actix_web::web::Bytes",
+            "actix_web::App",
+        ],
+    }
+}
 
-    let file_path = "src/main.rs";
-    let content = read_file(file_path)?;
+/// Put new tests before the first existing test, or at the end of the test
+/// module if there aren't any tests yet, and import anything that they need.
+/// Make a test module if we need to.
+fn test_edits(
+    actix_version: ActixVersion,
+    syntax_tree: &syn::File,
+    test: String,
+) -> Result<Vec<Edit>> {
+    let imports = test_imports(actix_version);
+    let test_module = find_test_module(syntax_tree);
+    let at = match (find_test_attr(syntax_tree), &test_module) {
+        (Ok(existing_test), _) => existing_test.start,
+        (Err(_), Some(test_module)) => test_module.end,
+        (Err(_), None) => {
+            return Ok(vec![Edit::insert(
+                end_of_file(syntax_tree)?,
+                format_test_module(imports, &test),
+            )])
+        }
+    };
+    let mut edits = match &test_module {
+        Some(test_module) => import_edits(
+            test_module.items,
+            Some(&syntax_tree.items),
+            test_module.end,
+            imports,
+        ),
+        None => import_edits(&syntax_tree.items, None, at, imports),
+    };
+    edits.push(Edit::insert(at, test));
+    Ok(edits)
+}
+
+pub async fn new_test(options: GenerateOptions, test_options: TestOptions) -> Result<()> {
+    generate_test(options, test_options, TestKind::Regression).await
+}
+
+pub async fn new_golden_test(options: GenerateOptions, test_options: TestOptions) -> Result<()> {
+    generate_test(options, test_options, TestKind::Golden).await
+}
+
+/// The kinds of test that we make from traces.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TestKind {
+    /// Reproduces an error, so that it can be fixed.
+    Regression,
+    /// Pins down a successful response, so that it doesn't change by
+    /// accident.
+    Golden,
+}
+
+impl TestKind {
+    fn default_status(self) -> StatusFilter {
+        let status = match self {
+            TestKind::Regression => "5xx",
+            TestKind::Golden => "2xx",
+        };
+        status.parse().unwrap()
+    }
+
+    /// The response headers that the test checks, if the trace has them.
+    fn asserted_headers(self) -> &'static [&'static str] {
+        match self {
+            TestKind::Regression => &[],
+            TestKind::Golden => &["content-type", "location"],
+        }
+    }
+
+    fn command(self) -> &'static str {
+        match self {
+            TestKind::Regression => "new-test",
+            TestKind::Golden => "new-golden-test",
+        }
+    }
+
+    fn file_prefix(self) -> &'static str {
+        match self {
+            TestKind::Regression => "regression",
+            TestKind::Golden => "golden",
+        }
+    }
+}
+
+async fn generate_test(
+    options: GenerateOptions,
+    test_options: TestOptions,
+    kind: TestKind,
+) -> Result<()> {
+    let status = test_options.status.unwrap_or_else(|| kind.default_status());
+    let mut state = State::load()?;
+    let traces: Vec<_> = if test_options.all {
+        traces_matching(status)
+            .await?
+            .filter(|trace| !state.is_handled(&trace.id))
+            .collect()
+    } else {
+        vec![latest_trace_matching(status, &state).await?]
+    };
+    if traces.is_empty() {
+        anyhow::bail!("no new traces found for status {}", status);
+    }
+    write_tests(&options, &test_options, kind, &traces, &mut state)
+}
+
+/// Make a regression test for `trace`, and remember that it's been handled.
+pub(crate) fn new_test_for_trace(
+    options: &GenerateOptions,
+    trace: &RequestTrace,
+    state: &mut State,
+) -> Result<()> {
+    write_tests(
+        options,
+        &TestOptions::default(),
+        TestKind::Regression,
+        std::slice::from_ref(trace),
+        state,
+    )
+}
+
+/// Make tests for `traces`, and remember that they've been handled.
+fn write_tests(
+    options: &GenerateOptions,
+    test_options: &TestOptions,
+    kind: TestKind,
+    traces: &[RequestTrace],
+    state: &mut State,
+) -> Result<()> {
+    // Integration tests can only see what the library target exports, so
+    // that's where the handlers need to be.
+    let source_path = match test_options.integration {
+        Some(_) => "src/lib.rs",
+        None => "src/main.rs",
+    };
+    let content = read_file(source_path)?;
+
+    let syntax_tree = parse_file(source_path, &content)?;
+    let actix_version = options.actix_version()?;
+
+    let test_path = |test: &NewTest| match test_options.integration {
+        Some(layout) => layout.test_path(test),
+        None => source_path.to_string(),
+    };
+    let exists = |test: &NewTest| match test_options.integration {
+        Some(_) => has_test_in_file(&test_path(test), &test.name),
+        None => has_test(&syntax_tree, &test.name),
+    };
+    let tests = tests_from_traces(
+        actix_version,
+        test_options,
+        kind,
+        traces,
+        &syntax_tree,
+        exists,
+        state,
+    )?;
+    if tests.is_empty() {
+        return state.save(&options.output);
+    }
+
+    for fixture in tests.iter().filter_map(|test| test.fixture.as_ref()) {
+        options
+            .output
+            .write_binary_file(&fixture.path, &fixture.contents)?;
+    }
+    match test_options.integration {
+        Some(_) => {
+            let manifest = read_manifest(MANIFEST_PATH)?;
+            let library = library_name(&manifest)
+                .with_context(|| format!("no package name in {:?}", MANIFEST_PATH))?;
+            let mut tests_by_path = std::collections::BTreeMap::<_, Vec<_>>::new();
+            for test in &tests {
+                tests_by_path.entry(test_path(test)).or_default().push(test);
+            }
+            for (path, tests) in tests_by_path {
+                let old = std::fs::read_to_string(&path).unwrap_or_default();
+                let new = add_integration_tests(actix_version, &library, &tests, &path, &old)?;
+                options.output.write_rust_file(&path, &old, &new)?;
+            }
+        }
+        None => {
+            let code: String = tests.iter().map(|test| test.code.as_str()).collect();
+            let edits = test_edits(actix_version, &syntax_tree, code)?;
+            options.output.write_rust_file(
+                source_path,
+                &content,
+                &apply_edits(&content, edits)?,
+            )?;
+        }
+    }
+    for test in &tests {
+        let snapshot = match &test.snapshot {
+            Some(snapshot) => snapshot,
+            None => continue,
+        };
+        let path = match test_options.integration {
+            Some(_) => snapshot_path("tests", &file_stem(&test_path(test)), &test.name),
+            None => {
+                let manifest = read_manifest(MANIFEST_PATH)?;
+                let crate_name = crate_name(&manifest)
+                    .with_context(|| format!("no package name in {:?}", MANIFEST_PATH))?;
+                snapshot_path(
+                    "src",
+                    &test_module_path(&crate_name, &syntax_tree),
+                    &test.name,
+                )
+            }
+        };
+        let old = std::fs::read_to_string(&path).unwrap_or_default();
+        let new = format_snapshot(&test_path(test), snapshot);
+        options.output.write_file(&path, &old, &new)?;
+    }
+    let dependencies = tests
+        .into_iter()
+        .flat_map(|test| test.dependencies)
+        .collect();
+    ensure_test_dependencies(options, actix_version, dependencies)?;
+    state.save(&options.output)
+}
+
+/// Make a test for each distinct request in `traces` that doesn't have one
+/// yet, and remember which tests cover which traces.
+///
+/// Requests are the same if they have the same method, route and body. If a
+/// test name is already taken, either by another request in `traces` or by
+/// a test that `exists`, then it gets a hash of the request on the end. That
+/// way, names don't depend on the order of the traces, and a request that
+/// comes in again ends up with the same name, so it can be skipped.
+fn tests_from_traces(
+    actix_version: ActixVersion,
+    test_options: &TestOptions,
+    kind: TestKind,
+    traces: &[RequestTrace],
+    syntax_tree: &syn::File,
+    exists: impl Fn(&NewTest) -> bool,
+    state: &mut State,
+) -> Result<Vec<NewTest>> {
+    // Each distinct request, with the ids of all of the traces that made it.
+    let mut seen: HashMap<_, usize> = HashMap::new();
+    let mut distinct: Vec<(_, _, Vec<String>)> = vec![];
+    for trace in traces {
+        let request = trace.request.parse()?;
+        let key = (
+            trace.request.method.clone(),
+            trace.request.route_path().to_string(),
+            request.bytes().to_vec(),
+        );
+        match seen.get(&key) {
+            Some(&index) => distinct[index].2.push(trace.id.clone()),
+            None => {
+                seen.insert(key.clone(), distinct.len());
+                distinct.push((trace, key, vec![trace.id.clone()]));
+            }
+        }
+    }
+
+    let mut tests = vec![];
+    for (trace, (method, route, body), trace_ids) in distinct {
+        let request_hash = format!(
+            "{:08x}",
+            fnv1a(&[method.as_bytes(), route.as_bytes(), &body]) as u32
+        );
+        if let Some(function) = state.tested(&request_hash).map(String::from) {
+            eprintln!(
+                "skipping {} {}, because {} already covers it",
+                method, route, function
+            );
+            for trace_id in &trace_ids {
+                state.record(trace_id, kind.command(), &function);
+            }
+            continue;
+        }
+        match test_from_trace(actix_version, test_options, kind, trace, syntax_tree, None) {
+            Ok(test) => tests.push((trace, request_hash, NewTest { trace_ids, ..test })),
+            // One bad trace shouldn't stop us from making the rest of the
+            // tests.
+            Err(e) if test_options.all => {
+                eprintln!("warning: skipping {} {}: {:#}", method, route, e)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    let mut name_counts = HashMap::<String, usize>::new();
+    for (_, _, test) in &tests {
+        *name_counts.entry(test.name.clone()).or_default() += 1;
+    }
+    let mut new_tests = vec![];
+    for (trace, request_hash, test) in tests {
+        let test = match name_counts[&test.name] == 1 && !exists(&test) {
+            true => test,
+            false => NewTest {
+                trace_ids: test.trace_ids,
+                ..test_from_trace(
+                    actix_version,
+                    test_options,
+                    kind,
+                    trace,
+                    syntax_tree,
+                    Some(&request_hash),
+                )?
+            },
+        };
+        let function = format!("test_{}", test.name);
+        for trace_id in &test.trace_ids {
+            state.record(trace_id, kind.command(), &function);
+        }
+        state.record_test(&request_hash, &function);
+        // The hash is of the request, so this is the same test.
+        if test.name.ends_with(&request_hash) && exists(&test) {
+            eprintln!("skipping {}, because it already exists", function);
+            continue;
+        }
+        new_tests.push(test);
+    }
+    Ok(new_tests)
+}
+
+/// Whether there's already a `test_{test_name}` fn, either at the top level
+/// or in the test module.
+fn has_test(syntax_tree: &syn::File, test_name: &str) -> bool {
+    let fn_name = format!("test_{}", test_name);
+    find_fn(syntax_tree, &fn_name).is_some()
+        || find_test_module(syntax_tree).is_some_and(|test_module| {
+            test_module.items.iter().any(|item| match item {
+                syn::Item::Fn(item_fn) => item_fn.sig.ident == fn_name,
+                _ => false,
+            })
+        })
+}
+
+fn has_test_in_file(file_path: &str, test_name: &str) -> bool {
+    let content = match std::fs::read_to_string(file_path) {
+        Ok(content) => content,
+        Err(_) => return false,
+    };
+    parse_file(file_path, &content).is_ok_and(|syntax_tree| has_test(&syntax_tree, test_name))
+}
+
+/// Where to put generated tests, if not in the inline test module.
+#[derive(Debug, Clone, Copy)]
+pub enum IntegrationLayout {
+    /// `tests/regression_500_index_param_boom.rs`, for a 500 response
+    PerTest,
+    /// `tests/regression_index.rs`, with every test for the `index` handler.
+    PerRoute,
+}
+
+impl std::str::FromStr for IntegrationLayout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "per-test" => Ok(IntegrationLayout::PerTest),
+            "per-route" => Ok(IntegrationLayout::PerRoute),
+            _ => anyhow::bail!("expected per-test or per-route, not {:?}", s),
+        }
+    }
+}
+
+impl IntegrationLayout {
+    fn test_path(self, test: &NewTest) -> String {
+        match self {
+            IntegrationLayout::PerTest => format!(
+                "tests/{}_{}_{}.rs",
+                test.kind.file_prefix(),
+                test.status,
+                test.name
+            ),
+            IntegrationLayout::PerRoute => {
+                format!("tests/{}_{}.rs", test.kind.file_prefix(), test.handler_name)
+            }
+        }
+    }
+}
+
+/// Add `tests` to the integration test file at `test_path`, which currently
+/// contains `content` (if it exists at all).
+fn add_integration_tests(
+    actix_version: ActixVersion,
+    library: &str,
+    tests: &[&NewTest],
+    test_path: &str,
+    content: &str,
+) -> Result<String> {
+    let mut handler_imports: Vec<_> = tests
+        .iter()
+        .map(|test| format!("{}::{}", library, test.handler_name))
+        .collect();
+    handler_imports.dedup();
+    let mut imports = test_imports(actix_version).to_vec();
+    imports.extend(handler_imports.iter().map(String::as_str));
+    let code: String = tests.iter().map(|test| test.code.as_str()).collect();
 
-    let edits = edits_for_new_test(trace, &content)?;
+    if content.trim().is_empty() {
+        // `format_uses()` is for inside a test module, so it's indented.
+        let uses: String = imports
+            .iter()
+            .map(|import| format!("use {};\n", import))
+            .collect();
+        return Ok(format!("{}{}", uses, code));
+    }
+    let syntax_tree = parse_file(test_path, content)?;
+    let mut edits = import_edits(
+        &syntax_tree.items,
+        None,
+        first_item(&syntax_tree)?,
+        &imports,
+    );
+    edits.push(Edit::insert(end_of_file(&syntax_tree)?, code));
+    apply_edits(content, edits)
+}
 
-    write_file(file_path, &content, edits)
+/// `"tests/regression_index.rs"` -> `"regression_index"`
+fn file_stem(path: &str) -> String {
+    std::path::Path::new(path)
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
 }
 
-fn edits_for_new_test(trace: RequestTrace, content: &str) -> Result<Vec<(LineColumn, String)>> {
-    let handler_name = find_handler_function_name(&content, &trace.request.route_path())?;
-    let existing_test = find_test_attr(&content)?;
+/// Everything that goes into adding a regression test.
+struct NewTest {
+    /// The name of the test function, without the `test_` prefix.
+    name: String,
+    /// The traces that the test covers.
+    trace_ids: Vec<String>,
+    kind: TestKind,
+    handler_name: String,
+    /// The status that the test expects.
+    status: u32,
+    code: String,
+    fixture: Option<Fixture>,
+    /// What to seed the test's insta snapshot with.
+    snapshot: Option<String>,
+    /// Crates that the test needs, on top of `test_dependencies()`.
+    dependencies: Vec<Dependency>,
+}
+
+/// A file that a generated test loads with `include_bytes!()`.
+#[derive(Debug)]
+struct Fixture {
+    path: String,
+    contents: Vec<u8>,
+}
+
+const FIXTURES_DIR: &str = "tests/fixtures";
+
+/// `name_suffix` goes on the end of the test name, to tell it apart from
+/// other tests for the same URI.
+fn test_from_trace(
+    actix_version: ActixVersion,
+    test_options: &TestOptions,
+    kind: TestKind,
+    trace: &RequestTrace,
+    syntax_tree: &syn::File,
+    name_suffix: Option<&str>,
+) -> Result<NewTest> {
+    // The handler might be inside a `web::scope()`, with a relative route.
+    let scope = find_service_registration(syntax_tree, trace.request.route_path())
+        .ok()
+        .and_then(|registration| registration.scope);
+    let route_path = scoped_route(scope.as_deref(), trace.request.route_path());
+    let handler_name = find_handler_function_name(syntax_tree, &trace.request.method, route_path)?;
+    let service = scoped_service(scope.as_deref(), &handler_name);
+    let mut test_name = regression_test_name(&handler_name, &trace.request.uri);
+    if let Some(name_suffix) = name_suffix {
+        test_name = format!("{}_{}", test_name, name_suffix);
+    }
+
+    let request = trace.request.parse()?;
+    let request_spec = RequestSpec {
+        uri: &trace.request.uri,
+        method: &trace.request.method,
+        content_type: request.header("content-type"),
+        body: request.bytes(),
+    };
+    let response = trace.response.parse()?;
+    // Redacted and anonymised values that the real server won't send back.
+    let request_text = format!(
+        "{} {}",
+        trace.request.uri,
+        String::from_utf8_lossy(request.bytes())
+    );
+    let is_unexpected =
+        |text: &str| is_redacted(text) || has_unexplained_fakes(text, &request_text);
+    let headers: Vec<_> = kind
+        .asserted_headers()
+        .iter()
+        .filter_map(|&name| Some((name, response.header(name)?)))
+        .filter(|(_, value)| !is_unexpected(value))
+        .collect();
+    let json = match response.is_json() {
+        true => serde_json::from_slice::<serde_json::Value>(response.bytes()).ok(),
+        false => None,
+    };
+    let mut fixture = None;
+    let mut snapshot = None;
+    let mut dependencies = vec![];
+    let expected_body = if let Some(mut value) = json {
+        let mut ignored_fields = test_options.ignore_field.clone();
+        for field in fields_where(&value, &is_unexpected) {
+            if !ignored_fields.contains(&field) {
+                ignored_fields.push(field);
+            }
+        }
+        strip_fields(&mut value, &ignored_fields);
+        dependencies.push(serde_json_dependency());
+        if test_options.snapshot {
+            dependencies.push(insta_dependency());
+            snapshot = Some(serde_json::to_string_pretty(&value)?);
+            ExpectedBody::JsonSnapshot { ignored_fields }
+        } else {
+            ExpectedBody::Json {
+                value: json_macro_literal(&value, 8),
+                ignored_fields,
+            }
+        }
+    } else if is_binary(&response) {
+        let path = format!("{}/{}.bin", FIXTURES_DIR, test_name);
+        let expected_body = format!(
+            "include_bytes!(concat!(env!(\"CARGO_MANIFEST_DIR\"), \"/{}\"))",
+            path
+        );
+        fixture = Some(Fixture {
+            path,
+            contents: response.bytes().to_vec(),
+        });
+        ExpectedBody::Bytes(expected_body)
+    } else if is_unexpected(response.text()?) {
+        // Text bodies are compared whole, so there's nothing that we can
+        // leave out.
+        eprintln!(
+            "note: test_{} won't check the body, because it had secrets or personal data in it",
+            test_name
+        );
+        ExpectedBody::Unchecked
+    } else if test_options.snapshot {
+        dependencies.push(insta_dependency());
+        snapshot = Some(response.text()?.to_string());
+        ExpectedBody::TextSnapshot
+    } else {
+        ExpectedBody::Bytes(byte_string_literal(response.bytes()))
+    };
 
     let skeleton_test = format_regression_test(
-        &handler_name,
-        &trace.request.uri,
-        &trace.response.get_body()?,
+        actix_version,
+        &test_name,
+        &service,
+        &request_spec,
+        trace.response.status_code,
+        &headers,
+        &expected_body,
     );
-    Ok(vec![(existing_test.start, skeleton_test)])
+    Ok(NewTest {
+        name: test_name,
+        trace_ids: vec![trace.id.clone()],
+        kind,
+        handler_name,
+        status: trace.response.status_code,
+        code: skeleton_test,
+        fixture,
+        snapshot,
+        dependencies,
+    })
+}
+
+/// The `module_path!()` of the test that `test_edits()` would add, with
+/// `::`s replaced by `__`s, like insta does.
+fn test_module_path(crate_name: &str, syntax_tree: &syn::File) -> String {
+    match find_test_module(syntax_tree) {
+        Some(test_module) => format!("{}__{}", crate_name, test_module.name),
+        None => format!("{}__tests", crate_name),
+    }
+}
+
+/// Where insta looks for the snapshot of the test called `test_{test_name}`,
+/// in a file in `dir`.
+fn snapshot_path(dir: &str, module_path: &str, test_name: &str) -> String {
+    format!("{}/snapshots/{}__{}.snap", dir, module_path, test_name)
+}
+
+fn format_snapshot(source: &str, contents: &str) -> String {
+    format!(
+        "---\nsource: {}\nexpression: body\n---\n{}\n",
+        source, contents
+    )
+}
+
+/// The names of fields with string values that `predicate` matches.
+fn fields_where(value: &serde_json::Value, predicate: &dyn Fn(&str) -> bool) -> Vec<String> {
+    let mut fields = vec![];
+    let mut values = vec![value];
+    while let Some(value) = values.pop() {
+        match value {
+            serde_json::Value::Object(map) => {
+                for (key, value) in map {
+                    match value.as_str() {
+                        Some(text) if predicate(text) => {
+                            if !fields.contains(key) {
+                                fields.push(key.clone());
+                            }
+                        }
+                        _ => values.push(value),
+                    }
+                }
+            }
+            serde_json::Value::Array(array) => values.extend(array),
+            _ => {}
+        }
+    }
+    fields
+}
+
+/// Remove `fields` from every object in `value`.
+fn strip_fields(value: &mut serde_json::Value, fields: &[String]) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for field in fields {
+                map.remove(field);
+            }
+            for value in map.values_mut() {
+                strip_fields(value, fields);
+            }
+        }
+        serde_json::Value::Array(values) => {
+            for value in values {
+                strip_fields(value, fields);
+            }
+        }
+        _ => {}
+    }
 }
 
-fn format_handler_fn(handler_name: &str, route_path: &str) -> String {
+/// The contents of a `serde_json::json!()` call that makes `value`, with
+/// the closing bracket indented by `indent` spaces.
+///
+/// rustfmt leaves macro contents alone, so we have to lay it out ourselves.
+/// Strings are written as Rust literals because JSON escapes like `\u00e9`
+/// aren't valid Rust.
+fn json_macro_literal(value: &serde_json::Value, indent: usize) -> String {
+    use serde_json::Value;
+    let inner = " ".repeat(indent + 4);
+    let outer = " ".repeat(indent);
+    match value {
+        Value::Null => "null".into(),
+        Value::Bool(b) => b.to_string(),
+        // Make sure that floats stay floats, rather than being written out
+        // as an integer that's too big for Rust. Integers without a suffix
+        // are i32s, so bigger ones (like timestamps in ms) need one.
+        Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) if (i32::MIN as i64..=i32::MAX as i64).contains(&i) => i.to_string(),
+            (Some(i), _) => format!("{}i64", i),
+            (None, Some(u)) => format!("{}u64", u),
+            (None, None) => format!("{:?}", n.as_f64().unwrap_or_default()),
+        },
+        Value::String(s) => format!("{:?}", s),
+        Value::Array(values) if values.is_empty() => "[]".into(),
+        Value::Array(values) => {
+            let values: String = values
+                .iter()
+                .map(|value| format!("{}{},\n", inner, json_macro_literal(value, indent + 4)))
+                .collect();
+            format!("[\n{}{}]", values, outer)
+        }
+        Value::Object(map) if map.is_empty() => "{}".into(),
+        Value::Object(map) => {
+            let fields: String = map
+                .iter()
+                .map(|(key, value)| {
+                    format!(
+                        "{}{:?}: {},\n",
+                        inner,
+                        key,
+                        json_macro_literal(value, indent + 4)
+                    )
+                })
+                .collect();
+            format!("{{\n{}{}}}", fields, outer)
+        }
+    }
+}
+
+/// Whether a body is better off in a fixture file than in the source.
+fn is_binary(response: &HttpResponse) -> bool {
+    match response.text() {
+        Ok(text) => text
+            .chars()
+            .any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t')),
+        Err(_) => true,
+    }
+}
+
+/// `b"..."`, with anything that isn't printable ASCII escaped.
+fn byte_string_literal(bytes: &[u8]) -> String {
+    let mut literal = String::from("b\"");
+    for &byte in bytes {
+        match byte {
+            b'"' => literal.push_str("\\\""),
+            b'\\' => literal.push_str("\\\\"),
+            b'\n' => literal.push_str("\\n"),
+            b'\r' => literal.push_str("\\r"),
+            b'\t' => literal.push_str("\\t"),
+            b' '..=b'~' => literal.push(byte as char),
+            _ => literal.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// `("index", "/?param=boom")` -> `"index_param_boom"`
+fn regression_test_name(handler_name: &str, uri: &str) -> String {
+    let safe_name = uri.replace(|c: char| !c.is_ascii_lowercase(), "_");
+    let suffix = safe_name.trim_start_matches('_');
+    format!("{}_{}", handler_name, suffix)
+}
+
+/// The bits of generated code that differ between actix-web versions.
+struct Syntax {
+    test_attr: &'static str,
+    async_: &'static str,
+    await_: &'static str,
+    mut_: &'static str,
+    read_body: &'static str,
+}
+
+fn syntax(actix_version: ActixVersion) -> Syntax {
+    match actix_version {
+        ActixVersion::V1 => Syntax {
+            test_attr: "#[test]",
+            async_: "",
+            await_: "",
+            mut_: "mut ",
+            read_body: "atest::read_body(resp)",
+        },
+        ActixVersion::V2 | ActixVersion::V3 => Syntax {
+            test_attr: "#[actix_rt::test]",
+            async_: "async ",
+            await_: ".await",
+            mut_: "mut ",
+            read_body: "atest::read_body(resp).await",
+        },
+        ActixVersion::V4 => Syntax {
+            test_attr: "#[actix_web::test]",
+            async_: "async ",
+            await_: ".await",
+            mut_: "",
+            read_body: "body::to_bytes(resp.into_body()).await.unwrap()",
+        },
+    }
+}
+
+fn format_handler_fn(actix_version: ActixVersion, handler_name: &str, route_path: &str) -> String {
     // Ignore the whitespace. Rustfmt will strip it all out.
     format!(
         r#"
 
 #[get("{route_path}")]
-async fn {handler_name}() -> impl Responder {{
+{async_}fn {handler_name}() -> impl Responder {{
     "TODO: implement this handler"
 }}
 
 "#,
         route_path = route_path,
+        async_ = syntax(actix_version).async_,
         handler_name = handler_name,
     )
 }
 
-fn format_integration_test(handler_name: &str, uri: &str) -> String {
+fn format_integration_test(
+    actix_version: ActixVersion,
+    handler_name: &str,
+    service: &str,
+    uri: &str,
+) -> String {
+    let Syntax {
+        test_attr,
+        async_,
+        await_,
+        mut_,
+        read_body,
+    } = syntax(actix_version);
     // Ignore the whitespace. Rustfmt will strip it all out.
     format!(
         r#"
 
-    #[actix_rt::test]
-    async fn test_{handler_name}() {{
-        let mut app = atest::init_service(App::new().service({handler_name})).await;
+    {test_attr}
+    {async_}fn test_{handler_name}() {{
+        let {mut_}app = atest::init_service(App::new().service({service})){await_};
 
         let req = atest::TestRequest::with_uri("{uri}").to_request();
-        let resp = atest::call_service(&mut app, req).await;
+        let resp = atest::call_service(&{mut_}app, req){await_};
 
         dbg!(resp.status());
         assert!(resp.status().is_success());
 
-        let bytes = atest::read_body(resp).await;
+        let bytes = {read_body};
         assert_eq!(
             bytes,
             Bytes::from_static(b"TODO: implement this handler")
@@ -121,50 +1076,353 @@ fn format_integration_test(handler_name: &str, uri: &str) -> String {
     }}
 
 "#,
+        test_attr = test_attr,
+        async_ = async_,
+        await_ = await_,
+        mut_ = mut_,
+        read_body = read_body,
         handler_name = handler_name,
+        service = service,
         uri = uri,
     )
 }
 
-fn format_regression_test(handler_name: &str, uri: &str, response_body: &str) -> String {
-    let safe_name = uri.replace(|c: char| !c.is_ascii_lowercase(), "_");
-    let suffix = safe_name.trim_start_matches('_');
+fn format_test_module(imports: &[&str], tests: &str) -> String {
     // Ignore the whitespace. Rustfmt will strip it all out.
     format!(
         r#"
 
-    #[actix_rt::test]
-    async fn test_{handler_name}_{suffix}() {{
-        let mut app = atest::init_service(App::new().service({handler_name})).await;
+#[cfg(test)]
+mod tests {{
+    use super::*;
+{uses}
+{tests}}}
+"#,
+        uses = format_uses(imports),
+        tests = tests,
+    )
+}
 
-        let req = atest::TestRequest::with_uri("{uri}").to_request();
-        let resp = atest::call_service(&mut app, req).await;
+/// `["actix_web::App", "actix_web::web::Bytes"]` ->
+/// `"use actix_web::{App, web::Bytes};"`
+fn format_uses(imports: &[&str]) -> String {
+    let mut crates: Vec<(&str, Vec<&str>)> = Vec::new();
+    for import in imports {
+        let mut parts = import.splitn(2, "::");
+        let krate = parts.next().unwrap_or_default();
+        let rest = parts.next().unwrap_or_default();
+        match crates.iter_mut().find(|(k, _)| *k == krate) {
+            Some((_, rests)) => rests.push(rest),
+            None => crates.push((krate, vec![rest])),
+        }
+    }
+    crates
+        .into_iter()
+        .map(|(krate, rests)| match rests.as_slice() {
+            [rest] => format!("    use {}::{};\n", krate, rest),
+            _ => format!("    use {}::{{{}}};\n", krate, rests.join(", ")),
+        })
+        .collect()
+}
 
-        assert_eq!(resp.status(), 500);
+/// What a regression test expects the response body to be.
+enum ExpectedBody {
+    /// An expression for a `&'static [u8]`.
+    Bytes(String),
+    /// The contents of a `serde_json::json!()`, and the fields to leave out
+    /// of the comparison.
+    Json {
+        value: String,
+        ignored_fields: Vec<String>,
+    },
+    /// Compare the body with an insta snapshot.
+    TextSnapshot,
+    /// Compare the body with an insta snapshot, as JSON.
+    JsonSnapshot { ignored_fields: Vec<String> },
+    /// Don't check the body, because the trace only has placeholders for
+    /// parts of it.
+    Unchecked,
+}
 
-        let bytes = atest::read_body(resp).await;
+fn format_body_assertion(read_body: &str, expected_body: &ExpectedBody) -> String {
+    match expected_body {
+        ExpectedBody::Bytes(expected_body) => format!(
+            r#"
+        let bytes = {read_body};
         assert_eq!(
             bytes,
-            Bytes::from_static(b"{response_body}")
+            Bytes::from_static({expected_body})
         );
-    }}
+"#,
+            read_body = read_body,
+            expected_body = expected_body,
+        ),
+        ExpectedBody::Json {
+            value,
+            ignored_fields,
+        } => format!(
+            r#"
+        let bytes = {read_body};
+        {parse_json}
+        let expected = serde_json::json!({value});
+        assert_eq!(body, expected);
+"#,
+            read_body = read_body,
+            parse_json = format_parse_json(ignored_fields),
+            value = value,
+        ),
+        ExpectedBody::TextSnapshot => format!(
+            r#"
+        let bytes = {read_body};
+        let body = String::from_utf8(bytes.to_vec()).unwrap();
+        insta::assert_snapshot!(body);
+"#,
+            read_body = read_body,
+        ),
+        ExpectedBody::JsonSnapshot { ignored_fields } => format!(
+            r#"
+        let bytes = {read_body};
+        {parse_json}
+        insta::assert_json_snapshot!(body);
+"#,
+            read_body = read_body,
+            parse_json = format_parse_json(ignored_fields),
+        ),
+        ExpectedBody::Unchecked => r#"
+        // The body isn't checked, because the trace only has placeholders for
+        // some of it.
+"#
+        .into(),
+    }
+}
+
+/// Parse the response into `body`, leaving out `ignored_fields`.
+fn format_parse_json(ignored_fields: &[String]) -> String {
+    if ignored_fields.is_empty() {
+        return "let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();".into();
+    }
+    format!(
+        r#"let mut body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
 
+        fn strip_ignored_fields(value: &mut serde_json::Value) {{
+            match value {{
+                serde_json::Value::Object(fields) => {{
+                    for field in &[{ignored_fields}] {{
+                        fields.remove(*field);
+                    }}
+                    fields.values_mut().for_each(strip_ignored_fields);
+                }}
+                serde_json::Value::Array(values) => {{
+                    values.iter_mut().for_each(strip_ignored_fields)
+                }}
+                _ => {{}}
+            }}
+        }}
+        strip_ignored_fields(&mut body);
 "#,
-        handler_name = handler_name,
-        suffix = suffix,
-        uri = uri,
-        response_body = response_body,
+        ignored_fields = ignored_fields
+            .iter()
+            .map(|field| format!("{:?}", field))
+            .collect::<Vec<_>>()
+            .join(", "),
+    )
+}
+
+/// The request that a generated test makes.
+struct RequestSpec<'a> {
+    uri: &'a str,
+    method: &'a str,
+    content_type: Option<&'a str>,
+    body: &'a [u8],
+}
+
+/// The `TestRequest` builder calls that set up everything but the URI.
+fn format_request_builder(actix_version: ActixVersion, request: &RequestSpec) -> String {
+    let mut builder = String::new();
+    if request.method != "GET" {
+        let method = match request.method {
+            "POST" | "PUT" | "DELETE" | "HEAD" | "OPTIONS" | "CONNECT" | "PATCH" | "TRACE" => {
+                format!("actix_web::http::Method::{}", request.method)
+            }
+            _ => format!(
+                "actix_web::http::Method::from_bytes(b{:?}).unwrap()",
+                request.method
+            ),
+        };
+        builder.push_str(&format!("\n            .method({})", method));
+    }
+    if request.body.is_empty() {
+        return builder;
+    }
+    if let Some(content_type) = request.content_type {
+        builder.push_str(&match actix_version {
+            ActixVersion::V4 => format!(
+                "\n            .insert_header((\"content-type\", {:?}))",
+                content_type
+            ),
+            _ => format!(
+                "\n            .header(\"content-type\", {:?})",
+                content_type
+            ),
+        });
+    }
+    builder.push_str(&format!(
+        "\n            .set_payload(Bytes::from_static({}))",
+        byte_string_literal(request.body)
+    ));
+    builder
+}
+
+fn format_regression_test(
+    actix_version: ActixVersion,
+    test_name: &str,
+    service: &str,
+    request: &RequestSpec,
+    status: u32,
+    headers: &[(&str, &str)],
+    expected_body: &ExpectedBody,
+) -> String {
+    let Syntax {
+        test_attr,
+        async_,
+        await_,
+        mut_,
+        read_body,
+    } = syntax(actix_version);
+    // Ignore the whitespace. Rustfmt will strip it all out.
+    format!(
+        r#"
+
+    {test_attr}
+    {async_}fn test_{test_name}() {{
+        let {mut_}app = atest::init_service(App::new().service({service})){await_};
+
+        let req = atest::TestRequest::with_uri("{uri}"){request_builder}.to_request();
+        let resp = atest::call_service(&{mut_}app, req){await_};
+
+        assert_eq!(resp.status(), {status});
+{header_assertions}{body_assertion}    }}
+
+"#,
+        test_attr = test_attr,
+        async_ = async_,
+        await_ = await_,
+        mut_ = mut_,
+        test_name = test_name,
+        service = service,
+        uri = request.uri,
+        request_builder = format_request_builder(actix_version, request),
+        status = status,
+        header_assertions = headers
+            .iter()
+            .map(|(name, value)| format!(
+                "        assert_eq!(resp.headers().get({:?}).unwrap(), {:?});\n",
+                name, value
+            ))
+            .collect::<String>(),
+        body_assertion = format_body_assertion(read_body, expected_body),
     )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::list::{Request, Response};
+
+    fn get(uri: &str) -> RequestSpec<'_> {
+        RequestSpec {
+            uri,
+            method: "GET",
+            content_type: None,
+            body: b"",
+        }
+    }
+
+    fn trace(method: &str, uri: &str, status_code: u32, raw_response: &str) -> RequestTrace {
+        RequestTrace {
+            id: "1".into(),
+            start: "2020-02-27T10:57:08Z".into(),
+            request: Request {
+                method: method.into(),
+                uri: uri.into(),
+                raw: base64::encode(&format!("{} {} HTTP/1.1\r\n\r\n", method, uri)),
+            },
+            response: Response {
+                status_code,
+                raw: base64::encode(raw_response),
+            },
+        }
+    }
+
+    #[test]
+    fn test_edits_for_new_handler_without_root_route_or_tests() {
+        let syntax_tree = parse_file(
+            "src/main.rs",
+            r#"use actix_web::{get, App, HttpServer, Responder};
+
+#[get("/hello")]
+async fn hello() -> impl Responder {
+    "Hello world!"
+}
+
+#[actix_rt::main]
+async fn main() -> std::io::Result<()> {
+    HttpServer::new(|| App::new().service(hello))
+        .bind("127.0.0.1:8080")?
+        .run()
+        .await
+}
+"#,
+        )
+        .unwrap();
+        let edits = edits_for_new_handler(
+            ActixVersion::V3,
+            trace("GET", "/favicon.ico", 404, "HTTP/1.1 404 Not Found\r\n\r\n"),
+            &syntax_tree,
+        )
+        .unwrap();
+        assert_eq!(
+            edits
+                .iter()
+                .map(|edit| (edit.start.line, edit.start.column))
+                .collect::<Vec<_>>(),
+            vec![(6, 1), (14, 1), (10, 48)]
+        );
+        assert!(edits[1].text.starts_with("\n\n#[cfg(test)]\nmod tests {"));
+    }
+
+    #[test]
+    fn test_existing_handler() {
+        let syntax_tree = parse_file(
+            "src/main.rs",
+            r#"use actix_web::{get, Responder};
+
+#[get("/foo")]
+async fn foo() -> impl Responder {
+    "TODO: implement this handler"
+}
+"#,
+        )
+        .unwrap();
+        let existing = |method: &str, uri: &str| {
+            existing_handler(
+                &syntax_tree,
+                &trace(method, uri, 404, "HTTP/1.1 404 Not Found\r\n\r\n"),
+                &handler_name(uri),
+            )
+        };
+        assert_eq!(existing("GET", "/foo").unwrap().as_deref(), Some("foo"));
+        assert_eq!(existing("GET", "/bar").unwrap(), None);
+        assert_eq!(
+            existing("POST", "/foo").unwrap_err().to_string(),
+            "there's already a fn called foo, so can't make a handler for POST /foo"
+        );
+    }
 
     #[test]
     fn test_format_handler_fn() {
         assert_eq!(
-            format_handler_fn("faviconico", "/favicon.ico"),
+            format_handler_fn(ActixVersion::V3, "faviconico", "/favicon.ico"),
             r#"
 
 #[get("/favicon.ico")]
@@ -179,7 +1437,7 @@ async fn faviconico() -> impl Responder {
     #[test]
     fn test_format_integration_test() {
         assert_eq!(
-            format_integration_test("faviconico", "/favicon.ico"),
+            format_integration_test(ActixVersion::V3, "faviconico", "faviconico", "/favicon.ico"),
             r#"
 
     #[actix_rt::test]
@@ -206,7 +1464,15 @@ async fn faviconico() -> impl Responder {
     #[test]
     fn test_format_regression_test() {
         assert_eq!(
-            format_regression_test("index", "/?param=boom", "Some error message"),
+            format_regression_test(
+                ActixVersion::V3,
+                &regression_test_name("index", "/?param=boom"),
+                "index",
+                &get("/?param=boom"),
+                500,
+                &[],
+                &ExpectedBody::Bytes(byte_string_literal(b"Some error message"))
+            ),
             r#"
 
     #[actix_rt::test]
@@ -228,4 +1494,438 @@ async fn faviconico() -> impl Responder {
 "#
         )
     }
+
+    #[test]
+    fn test_format_integration_test_for_other_versions() {
+        assert_eq!(
+            format_integration_test(ActixVersion::V4, "index", "index", "/"),
+            r#"
+
+    #[actix_web::test]
+    async fn test_index() {
+        let app = atest::init_service(App::new().service(index)).await;
+
+        let req = atest::TestRequest::with_uri("/").to_request();
+        let resp = atest::call_service(&app, req).await;
+
+        dbg!(resp.status());
+        assert!(resp.status().is_success());
+
+        let bytes = body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(
+            bytes,
+            Bytes::from_static(b"TODO: implement this handler")
+        );
+    }
+
+"#
+        );
+        assert_eq!(
+            format_integration_test(ActixVersion::V1, "index", "index", "/"),
+            r#"
+
+    #[test]
+    fn test_index() {
+        let mut app = atest::init_service(App::new().service(index));
+
+        let req = atest::TestRequest::with_uri("/").to_request();
+        let resp = atest::call_service(&mut app, req);
+
+        dbg!(resp.status());
+        assert!(resp.status().is_success());
+
+        let bytes = atest::read_body(resp);
+        assert_eq!(
+            bytes,
+            Bytes::from_static(b"TODO: implement this handler")
+        );
+    }
+
+"#
+        );
+    }
+
+    #[test]
+    fn test_format_uses() {
+        assert_eq!(
+            format_uses(test_imports(ActixVersion::V4)),
+            "    use actix_web::{body, test as atest, web::Bytes, App};\n"
+        );
+    }
+
+    #[test]
+    fn test_byte_string_literal() {
+        assert_eq!(
+            byte_string_literal("{\"error\": \"C:\\ not found\"}\r\nnaïve".as_bytes()),
+            r#"b"{\"error\": \"C:\\ not found\"}\r\nna\xc3\xafve""#
+        );
+        let response = |raw: &[u8]| HttpResponse::parse(raw).unwrap();
+        assert!(!is_binary(&response(
+            "HTTP/1.1 200 OK\r\n\r\nnaïve\n".as_bytes()
+        )));
+        assert!(is_binary(&response(
+            b"HTTP/1.1 200 OK\r\n\r\n\x89PNG\r\n\x1a\n"
+        )));
+    }
+
+    #[test]
+    fn test_fields_where() {
+        let value = serde_json::json!({
+            "token": "REDACTED",
+            "user": {"email": "user-8b7d38c8@example.com", "id": 3},
+            "friends": [{"email": "user-00000000@example.com"}],
+        });
+        let request = r#"{"email":"user-8b7d38c8@example.com"}"#;
+        let is_unexpected = |text: &str| is_redacted(text) || has_unexplained_fakes(text, request);
+        assert_eq!(fields_where(&value, &is_unexpected), vec!["token", "email"]);
+    }
+
+    #[test]
+    fn test_json_macro_literal_numbers() {
+        let value = serde_json::json!([3, -4, 1700000000000i64, -3000000000i64, u64::MAX, 1.0]);
+        assert_eq!(
+            json_macro_literal(&value, 0),
+            "[\n    3,\n    -4,\n    1700000000000i64,\n    -3000000000i64,\n    18446744073709551615u64,\n    1.0,\n]"
+        );
+    }
+
+    #[test]
+    fn test_format_body_assertion_for_json() {
+        let mut value = serde_json::json!({
+            "id": 3,
+            "error": "naïve \u{1f}",
+            "items": [{"id": 1, "score": 1.5e300}],
+        });
+        strip_fields(&mut value, &["id".to_string()]);
+        assert_eq!(
+            format_body_assertion(
+                "atest::read_body(resp).await",
+                &ExpectedBody::Json {
+                    value: json_macro_literal(&value, 8),
+                    ignored_fields: vec!["id".into()],
+                }
+            ),
+            r#"
+        let bytes = atest::read_body(resp).await;
+        let mut body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+
+        fn strip_ignored_fields(value: &mut serde_json::Value) {
+            match value {
+                serde_json::Value::Object(fields) => {
+                    for field in &["id"] {
+                        fields.remove(*field);
+                    }
+                    fields.values_mut().for_each(strip_ignored_fields);
+                }
+                serde_json::Value::Array(values) => {
+                    values.iter_mut().for_each(strip_ignored_fields)
+                }
+                _ => {}
+            }
+        }
+        strip_ignored_fields(&mut body);
+
+        let expected = serde_json::json!({
+            "error": "naïve \u{1f}",
+            "items": [
+                {
+                    "score": 1.5e300,
+                },
+            ],
+        });
+        assert_eq!(body, expected);
+"#
+        );
+    }
+
+    #[test]
+    fn test_edits_for_new_snapshot_test() {
+        let syntax_tree = parse_file(
+            "src/main.rs",
+            r#"use actix_web::{get, Responder};
+
+#[get("/")]
+async fn index() -> impl Responder {
+    "TODO: implement this handler"
+}
+
+fn main() {}
+"#,
+        )
+        .unwrap();
+        let test = test_from_trace(
+            ActixVersion::V3,
+            &TestOptions {
+                status: None,
+                ignore_field: vec!["id".into()],
+                snapshot: true,
+                integration: None,
+                all: false,
+            },
+            TestKind::Regression,
+            &trace(
+                "GET",
+                "/?param=boom",
+                500,
+                "HTTP/1.1 500 Internal Server Error\r\nContent-Type: application/json\r\n\r\n{\"id\":7,\"error\":\"boom\"}",
+            ),
+            &syntax_tree,
+            None,
+        )
+        .unwrap();
+        assert_eq!(test.snapshot.unwrap(), "{\n  \"error\": \"boom\"\n}");
+        assert_eq!(
+            test.dependencies
+                .iter()
+                .map(|dependency| dependency.name)
+                .collect::<Vec<_>>(),
+            vec!["serde_json", "insta"]
+        );
+        assert!(test.code.contains("insta::assert_json_snapshot!(body);"));
+        assert_eq!(
+            snapshot_path("src", &test_module_path("my_app", &syntax_tree), &test.name),
+            "src/snapshots/my_app__tests__index_param_boom.snap"
+        );
+    }
+
+    #[test]
+    fn test_test_from_trace_with_redacted_body() {
+        let syntax_tree = parse_file(
+            "src/main.rs",
+            r#"use actix_web::{post, Responder};
+
+#[post("/login")]
+async fn login() -> impl Responder {
+    "TODO: implement this handler"
+}
+"#,
+        )
+        .unwrap();
+        let test = test_from_trace(
+            ActixVersion::V3,
+            &TestOptions::default(),
+            TestKind::Golden,
+            &trace(
+                "POST",
+                "/login",
+                302,
+                "HTTP/1.1 302 Found\r\n\
+                 Location: /home?token=REDACTED\r\n\
+                 Content-Type: application/x-www-form-urlencoded\r\n\
+                 \r\n\
+                 access_token=REDACTED&expires=3600",
+            ),
+            &syntax_tree,
+            None,
+        )
+        .unwrap();
+        assert!(test.code.contains(
+            r#"assert_eq!(resp.headers().get("content-type").unwrap(), "application/x-www-form-urlencoded");"#
+        ));
+        assert!(!test.code.contains("location"));
+        assert!(!test.code.contains("REDACTED"));
+        assert!(!test.code.contains("read_body"));
+
+        // Fakes that the request can't explain are left out too.
+        let test = test_from_trace(
+            ActixVersion::V3,
+            &TestOptions {
+                snapshot: true,
+                ..TestOptions::default()
+            },
+            TestKind::Regression,
+            &trace(
+                "POST",
+                "/login",
+                500,
+                "HTTP/1.1 500 Internal Server Error\r\n\r\nno account for user-8b7d38c8@example.com",
+            ),
+            &syntax_tree,
+            None,
+        )
+        .unwrap();
+        assert!(test.snapshot.is_none());
+        assert!(!test.code.contains("read_body"));
+    }
+
+    #[test]
+    fn test_add_integration_test() {
+        let test = NewTest {
+            name: "index_param_boom".into(),
+            trace_ids: vec!["1".into()],
+            kind: TestKind::Regression,
+            handler_name: "index".into(),
+            status: 500,
+            code: "\n\n#[actix_rt::test]\nasync fn test_index_param_boom() {}\n".into(),
+            fixture: None,
+            snapshot: None,
+            dependencies: vec![],
+        };
+        assert_eq!(
+            IntegrationLayout::PerTest.test_path(&test),
+            "tests/regression_500_index_param_boom.rs"
+        );
+        assert_eq!(
+            add_integration_tests(ActixVersion::V3, "my_app", &[&test], "tests/x.rs", "").unwrap(),
+            r#"use actix_web::test as atest;
+use actix_web::web::Bytes;
+use actix_web::App;
+use my_app::index;
+
+
+#[actix_rt::test]
+async fn test_index_param_boom() {}
+"#
+        );
+        assert_eq!(
+            add_integration_tests(
+                ActixVersion::V3,
+                "my_app",
+                &[&test],
+                "tests/x.rs",
+                "use actix_web::{test as atest, web::Bytes, App};\n\n#[actix_rt::test]\nasync fn test_other() {}\n"
+            )
+            .unwrap(),
+            r#"use actix_web::{test as atest, web::Bytes, App};
+use my_app::index;
+
+#[actix_rt::test]
+async fn test_other() {}
+
+#[actix_rt::test]
+async fn test_index_param_boom() {}
+
+"#
+        );
+    }
+
+    #[test]
+    fn test_format_golden_test() {
+        assert_eq!(
+            format_regression_test(
+                ActixVersion::V3,
+                &regression_test_name("login", "/login"),
+                "login",
+                &RequestSpec {
+                    uri: "/login",
+                    method: "POST",
+                    content_type: Some("application/x-www-form-urlencoded"),
+                    body: b"user=me",
+                },
+                303,
+                &[("location", "/home")],
+                &ExpectedBody::Bytes(byte_string_literal(b""))
+            ),
+            r#"
+
+    #[actix_rt::test]
+    async fn test_login_login() {
+        let mut app = atest::init_service(App::new().service(login)).await;
+
+        let req = atest::TestRequest::with_uri("/login")
+            .method(actix_web::http::Method::POST)
+            .header("content-type", "application/x-www-form-urlencoded")
+            .set_payload(Bytes::from_static(b"user=me")).to_request();
+        let resp = atest::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), 303);
+        assert_eq!(resp.headers().get("location").unwrap(), "/home");
+
+        let bytes = atest::read_body(resp).await;
+        assert_eq!(
+            bytes,
+            Bytes::from_static(b"")
+        );
+    }
+
+"#
+        )
+    }
+
+    #[test]
+    fn test_tests_from_traces() {
+        let syntax_tree = parse_file(
+            "src/main.rs",
+            r#"use actix_web::{post, Responder};
+
+#[post("/")]
+async fn index() -> impl Responder {
+    "TODO: implement this handler"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_index_a_e8f1a562() {}
+}
+"#,
+        )
+        .unwrap();
+        let post = |uri: &str, body: &str| {
+            let mut trace = trace(
+                "POST",
+                uri,
+                500,
+                "HTTP/1.1 500 Internal Server Error\r\n\r\n",
+            );
+            trace.request.raw = base64::encode(&format!(
+                "POST {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                uri,
+                body.len(),
+                body
+            ));
+            trace
+        };
+        let traces = vec![
+            post("/?a", "x"),
+            post("/?a", "x"),
+            post("/?a", "y"),
+            post("/?b", "z"),
+            post("/nowhere", "x"),
+        ];
+        let test_options = TestOptions {
+            status: None,
+            ignore_field: vec![],
+            snapshot: false,
+            integration: None,
+            all: true,
+        };
+        let exists = |test: &NewTest| has_test(&syntax_tree, &test.name);
+        let mut state = State::default();
+        let tests = tests_from_traces(
+            ActixVersion::V3,
+            &test_options,
+            TestKind::Regression,
+            &traces,
+            &syntax_tree,
+            exists,
+            &mut state,
+        )
+        .unwrap();
+        // The first request already has a test, from an earlier batch.
+        let names: Vec<_> = tests.iter().map(|test| test.name.as_str()).collect();
+        assert_eq!(names, vec!["index_a_e8f1a715", "index_b"]);
+        assert_eq!(state.tested("e8f1a562"), Some("test_index_a_e8f1a562"));
+        assert_eq!(state.tested("e8f1a715"), Some("test_index_a_e8f1a715"));
+
+        // Requests that have been seen before are skipped, even if their
+        // plain name is free. New ones don't take the names of old tests.
+        let tests = tests_from_traces(
+            ActixVersion::V3,
+            &test_options,
+            TestKind::Regression,
+            &[post("/?b", "z"), post("/?a", "y"), post("/?b", "w")],
+            &syntax_tree,
+            |test: &NewTest| test.name == "index_b",
+            &mut state,
+        )
+        .unwrap();
+        let names: Vec<_> = tests.iter().map(|test| test.name.as_str()).collect();
+        assert_eq!(names, vec!["index_b_e8f1aa7b"]);
+        assert!(tests[0]
+            .code
+            .contains(".set_payload(Bytes::from_static(b\"w\"))"));
+    }
 }
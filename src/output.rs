@@ -0,0 +1,123 @@
+//! Writing generated code to disk, or showing what would be written.
+
+use crate::rustfmt::rustfmt;
+use anyhow::{Context, Result};
+use similar::TextDiff;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Clone, Copy, Debug, Default)]
+pub struct OutputOptions {
+    /// print a unified diff of the planned edits instead of writing them
+    #[structopt(long)]
+    pub dry_run: bool,
+
+    /// print the diff and exit with an error if any edits would be made
+    ///
+    /// Useful in pre-commit hooks.
+    #[structopt(long)]
+    pub check: bool,
+
+    /// leave the generated code as it is, rather than running rustfmt on it
+    #[structopt(long)]
+    pub no_rustfmt: bool,
+}
+
+impl OutputOptions {
+    /// Like `write_file()`, but runs `new` through rustfmt first.
+    pub fn write_rust_file(&self, file_path: &str, old: &str, new: &str) -> Result<()> {
+        if self.no_rustfmt {
+            return self.write_file(file_path, old, new);
+        }
+        match rustfmt(new) {
+            Ok(formatted) => self.write_file(file_path, old, &formatted),
+            Err(e) => {
+                eprintln!("warning: not formatting {:?}: {:#}", file_path, e);
+                self.write_file(file_path, old, new)
+            }
+        }
+    }
+
+    /// Replace `old` (the current content of `file_path`) with `new`.
+    ///
+    /// New files can be written by passing an empty `old`.
+    pub fn write_file(&self, file_path: &str, old: &str, new: &str) -> Result<()> {
+        if old == new {
+            return Ok(());
+        }
+        if self.dry_run || self.check {
+            print!("{}", unified_diff(file_path, old, new));
+        }
+        if self.check {
+            anyhow::bail!("{:?} would be modified", file_path);
+        }
+        if self.dry_run {
+            return Ok(());
+        }
+        create_parent_dir(file_path)?;
+        std::fs::write(file_path, new).with_context(|| format!("writing {:?}", file_path))
+    }
+
+    /// Write `contents` to `file_path`, creating any missing directories.
+    ///
+    /// The contents might not be text, so dry runs only say what would be
+    /// written rather than showing a diff.
+    pub fn write_binary_file(&self, file_path: &str, contents: &[u8]) -> Result<()> {
+        if std::fs::read(file_path).ok().as_deref() == Some(contents) {
+            return Ok(());
+        }
+        if self.dry_run || self.check {
+            println!(
+                "Binary file b/{} would be written ({} bytes)",
+                file_path,
+                contents.len()
+            );
+        }
+        if self.check {
+            anyhow::bail!("{:?} would be modified", file_path);
+        }
+        if self.dry_run {
+            return Ok(());
+        }
+        create_parent_dir(file_path)?;
+        std::fs::write(file_path, contents).with_context(|| format!("writing {:?}", file_path))
+    }
+}
+
+pub(crate) fn create_parent_dir(file_path: &str) -> Result<()> {
+    match std::path::Path::new(file_path).parent() {
+        Some(dir) => std::fs::create_dir_all(dir).with_context(|| format!("creating {:?}", dir)),
+        None => Ok(()),
+    }
+}
+
+fn unified_diff(file_path: &str, old: &str, new: &str) -> String {
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .header(&format!("a/{}", file_path), &format!("b/{}", file_path))
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unified_diff() {
+        assert_eq!(
+            unified_diff(
+                "src/main.rs",
+                "fn main() {\n}\n",
+                "fn main() {\n}\n\nfn index() {\n}\n"
+            ),
+            r#"--- a/src/main.rs
++++ b/src/main.rs
+@@ -1,2 +1,5 @@
+ fn main() {
+ }
++
++fn index() {
++}
+"#
+        )
+    }
+}
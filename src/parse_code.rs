@@ -3,10 +3,112 @@
 //! for my purposes.
 
 use anyhow::{Context, Result};
-use proc_macro2::LineColumn;
-use quote::ToTokens;
-use syn::visit::visit_expr_method_call;
-use syn::{spanned::Spanned, visit::Visit, Attribute, ExprMethodCall, ItemFn};
+use proc_macro2::{Delimiter, LineColumn, TokenStream, TokenTree};
+use syn::visit::{visit_expr, visit_expr_call};
+use syn::{
+    spanned::Spanned, visit::Visit, Attribute, Expr, ExprCall, ExprLit, ExprMethodCall, FnArg,
+    Item, ItemFn, ItemMod, Lit, Local, Meta, MetaNameValue, NestedMeta, Pat, PatType, Stmt,
+};
+
+/// Parse `code`, which was read from `file_path`.
+///
+/// If the file doesn't parse (because you're half way through editing it,
+/// say) then we warn about it and carry on with the items that do parse,
+/// as long as the brackets are balanced.
+pub(crate) fn parse_file(file_path: &str, code: &str) -> Result<syn::File> {
+    let error = match syn::parse_file(code) {
+        Ok(syntax_tree) => return Ok(syntax_tree),
+        Err(e) => e,
+    };
+    let tokens: TokenStream = code.parse().map_err(|e: proc_macro2::LexError| {
+        // The span is the unmatched bracket, or wherever lexing gave up.
+        let start = e.span().start();
+        anyhow::anyhow!(
+            "could not parse {}:{}:{}: unbalanced brackets or unterminated literal",
+            file_path,
+            start.line,
+            start.column + 1
+        )
+    })?;
+    let start = error.span().start();
+    let message = format!(
+        "{}:{}:{}: {}",
+        file_path,
+        start.line,
+        start.column + 1,
+        error
+    );
+    match parse_items_leniently(tokens) {
+        Some(items) => {
+            eprintln!(
+                "warning: {} (carrying on with the items that do parse)",
+                message
+            );
+            Ok(syn::File {
+                shebang: None,
+                attrs: Vec::new(),
+                items,
+            })
+        }
+        None => anyhow::bail!("could not parse {}", message),
+    }
+}
+
+/// Split `tokens` into items and parse them one at a time, skipping any that
+/// don't parse. Inline modules are parsed recursively.
+fn parse_items_leniently(tokens: TokenStream) -> Option<Vec<Item>> {
+    let mut items = Vec::new();
+    let mut current = Vec::new();
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        let ends_item = match &token {
+            TokenTree::Punct(p) => p.as_char() == ';',
+            TokenTree::Group(g) if g.delimiter() == Delimiter::Brace => !matches!(
+                tokens.peek(),
+                Some(TokenTree::Punct(p)) if p.as_char() == ';'
+            ),
+            TokenTree::Group(g) if g.delimiter() == Delimiter::Bracket => {
+                // Inner attributes (`#![...]`) don't belong to any item.
+                if current.len() == 2 && current.iter().map(ToString::to_string).eq(["#", "!"]) {
+                    current.clear();
+                    continue;
+                }
+                false
+            }
+            _ => false,
+        };
+        current.push(token);
+        if ends_item {
+            items.extend(parse_item_leniently(std::mem::take(&mut current)));
+        }
+    }
+    if items.is_empty() {
+        None
+    } else {
+        Some(items)
+    }
+}
+
+fn parse_item_leniently(mut tokens: Vec<TokenTree>) -> Option<Item> {
+    let all_tokens: TokenStream = tokens.iter().cloned().collect();
+    if let Ok(item) = syn::parse2(all_tokens) {
+        return Some(item);
+    }
+    // Maybe it's a `mod name { ... }` with something broken inside.
+    let group = match tokens.pop()? {
+        TokenTree::Group(g) if g.delimiter() == Delimiter::Brace => g,
+        _ => return None,
+    };
+    let mut header: TokenStream = tokens.into_iter().collect();
+    header.extend(";".parse::<TokenStream>().ok());
+    let mut item_mod: ItemMod = syn::parse2(header).ok()?;
+    item_mod.semi = None;
+    item_mod.content = Some((
+        syn::token::Brace { span: group.span() },
+        parse_items_leniently(group.stream()).unwrap_or_default(),
+    ));
+    Some(Item::Mod(item_mod))
+}
 
 /// There can be only one
 fn highlander<T>(mut things: Vec<T>) -> Result<T> {
@@ -17,7 +119,7 @@ fn highlander<T>(mut things: Vec<T>) -> Result<T> {
 }
 
 fn first<T>(mut things: Vec<T>) -> Result<T> {
-    if things.len() < 1 {
+    if things.is_empty() {
         anyhow::bail!(
             "Found {} things. There should be at least one.",
             things.len()
@@ -26,21 +128,102 @@ fn first<T>(mut things: Vec<T>) -> Result<T> {
     Ok(things.swap_remove(0))
 }
 
-/// Find locations of `#[get("/")]`s from source code.
-pub(crate) fn find_handler_attr(code: &str) -> Result<Location> {
-    let mut visitor = AttrVisitor::new(r#"#[get("/")"#.into());
-    if let Ok(syntax_tree) = syn::parse_file(&code) {
-        visitor.visit_file(&syntax_tree);
-    }
+/// Find locations of route handler functions (the ones with `#[get("/")]`
+/// or similar) at the top level of the source code.
+pub(crate) fn find_handler_fns(syntax_tree: &syn::File) -> Vec<Location> {
+    syntax_tree
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Fn(item_fn) if item_fn.attrs.iter().any(|a| parse_route_attr(a).is_some()) => {
+                Some(Location {
+                    start: item_fn.span().start(),
+                    end: item_fn.span().end(),
+                })
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Find the location of the top-level function called `name`.
+pub(crate) fn find_fn(syntax_tree: &syn::File, name: &str) -> Option<Location> {
+    syntax_tree.items.iter().find_map(|item| match item {
+        Item::Fn(item_fn) if item_fn.sig.ident == name => Some(Location {
+            start: item_fn.span().start(),
+            end: item_fn.span().end(),
+        }),
+        _ => None,
+    })
+}
+
+/// Find the start of the first item in the source code.
+pub(crate) fn first_item(syntax_tree: &syn::File) -> Result<LineColumn> {
+    syntax_tree
+        .items
+        .first()
+        .map(|item| item.span().start())
+        .context("the file is empty")
+}
 
-    highlander(visitor.out).context("should be exactly one '/' handler")
+/// Find the end of the last item in the source code.
+pub(crate) fn end_of_file(syntax_tree: &syn::File) -> Result<LineColumn> {
+    syntax_tree
+        .items
+        .last()
+        .map(|item| item.span().end())
+        .context("the file is empty")
+}
+
+/// The inline `#[cfg(test)]` module.
+pub(crate) struct TestModule<'ast> {
+    pub(crate) name: String,
+    pub(crate) items: &'ast [Item],
+    /// The point just before the closing brace.
+    pub(crate) end: LineColumn,
+}
+
+/// Find the inline `#[cfg(test)]` module.
+pub(crate) fn find_test_module(syntax_tree: &syn::File) -> Option<TestModule<'_>> {
+    syntax_tree.items.iter().find_map(|item| match item {
+        Item::Mod(ItemMod {
+            attrs,
+            ident,
+            content: Some((brace, items)),
+            ..
+        }) if attrs.iter().any(is_cfg_test_attr) => {
+            let end = brace.span.end();
+            Some(TestModule {
+                name: ident.to_string(),
+                items,
+                end: LineColumn {
+                    line: end.line,
+                    column: end.column - 1,
+                },
+            })
+        }
+        _ => None,
+    })
 }
 
-/// Find locations of `#[actix_rt::test]`s from source code.
-pub(crate) fn find_test_attr(code: &str) -> Result<Location> {
-    let mut visitor = AttrVisitor::new("#[actix_rt::test]".into());
-    if let Ok(syntax_tree) = syn::parse_file(&code) {
-        visitor.visit_file(&syntax_tree);
+fn is_cfg_test_attr(attr: &Attribute) -> bool {
+    match attr.parse_meta() {
+        Ok(Meta::List(list)) if list.path.is_ident("cfg") => list.nested.iter().any(
+            |nested| matches!(nested, NestedMeta::Meta(Meta::Path(path)) if path.is_ident("test")),
+        ),
+        _ => false,
+    }
+}
+
+/// Find the first `#[actix_rt::test]` in the inline `#[cfg(test)]` module.
+///
+/// Tests anywhere else aren't a good place to put new ones.
+pub(crate) fn find_test_attr(syntax_tree: &syn::File) -> Result<Location> {
+    let test_module =
+        find_test_module(syntax_tree).context("Could not find a #[cfg(test)] module")?;
+    let mut visitor = AttrVisitor::new(is_test_attr);
+    for item in test_module.items {
+        visitor.visit_item(item);
     }
 
     first(visitor.out).context("Could not find any #[actix_rt::test] annotations")
@@ -52,15 +235,91 @@ pub(crate) struct Location {
     pub(crate) end: LineColumn,
 }
 
+/// The route macros that actix-web provides.
+const ROUTE_MACROS: &[&str] = &[
+    "get", "post", "put", "delete", "head", "connect", "options", "trace", "patch", "route",
+];
+
+/// A parsed `#[get("/route", ...)]` attribute.
+#[derive(Debug, PartialEq)]
+pub(crate) struct RouteAttr {
+    /// The HTTP methods that the handler responds to, in upper case.
+    pub(crate) methods: Vec<String>,
+    pub(crate) route: String,
+    /// Any other arguments, like `wrap = "..."`.
+    pub(crate) args: Vec<NestedMeta>,
+}
+
+/// Parse a route attribute, with or without the `actix_web::` prefix.
+pub(crate) fn parse_route_attr(attr: &Attribute) -> Option<RouteAttr> {
+    let segments: Vec<String> = attr
+        .path
+        .segments
+        .iter()
+        .map(|s| s.ident.to_string())
+        .collect();
+    let macro_name = match segments.as_slice() {
+        [name] => name,
+        [krate, name] if krate == "actix_web" => name,
+        _ => return None,
+    };
+    if !ROUTE_MACROS.contains(&macro_name.as_str()) {
+        return None;
+    }
+    let nested = match attr.parse_meta().ok()? {
+        Meta::List(list) => list.nested,
+        _ => return None,
+    };
+    let mut nested = nested.into_iter();
+    let route = match nested.next()? {
+        NestedMeta::Lit(Lit::Str(route)) => route.value(),
+        _ => return None,
+    };
+    let mut methods = Vec::new();
+    let mut args = Vec::new();
+    for arg in nested {
+        match &arg {
+            NestedMeta::Meta(Meta::NameValue(MetaNameValue {
+                path,
+                lit: Lit::Str(method),
+                ..
+            })) if macro_name == "route" && path.is_ident("method") => {
+                methods.push(method.value().to_uppercase())
+            }
+            _ => args.push(arg),
+        }
+    }
+    if macro_name != "route" {
+        methods.push(macro_name.to_uppercase());
+    }
+    Some(RouteAttr {
+        methods,
+        route,
+        args,
+    })
+}
+
+/// `#[test]` (actix-web 1.x), `#[actix_rt::test]` (2.x and 3.x) or
+/// `#[actix_web::test]` (4.x).
+fn is_test_attr(attr: &Attribute) -> bool {
+    let path: Vec<_> = attr
+        .path
+        .segments
+        .iter()
+        .map(|s| s.ident.to_string())
+        .collect();
+    path == ["test"] || path == ["actix_rt", "test"] || path == ["actix_web", "test"]
+}
+
 struct AttrVisitor {
-    searching_for: String,
+    matches: fn(&Attribute) -> bool,
     out: Vec<Location>,
 }
 
 impl AttrVisitor {
-    fn new(searching_for: String) -> Self {
+    fn new(matches: fn(&Attribute) -> bool) -> Self {
         AttrVisitor {
-            searching_for,
+            matches,
             out: Vec::default(),
         }
     }
@@ -68,11 +327,7 @@ impl AttrVisitor {
 
 impl<'ast> Visit<'ast> for AttrVisitor {
     fn visit_attribute(&mut self, i: &'ast Attribute) {
-        if i.to_token_stream()
-            .to_string()
-            .replace(" ", "")
-            .starts_with(&self.searching_for)
-        {
+        if (self.matches)(i) {
             self.out.push(Location {
                 start: i.pound_token.span().start(),
                 end: i.bracket_token.span.end(),
@@ -81,80 +336,303 @@ impl<'ast> Visit<'ast> for AttrVisitor {
     }
 }
 
-/// Find names of #[get("/route")] handler functions from source code.
-pub(crate) fn find_handler_function_name(code: &str, route: &str) -> Result<String> {
-    let mut visitor = FnVisitor::new(format!(r#"#[get("{}")]"#, route));
-    if let Ok(syntax_tree) = syn::parse_file(&code) {
-        visitor.visit_file(&syntax_tree);
-    }
-
-    highlander(visitor.out).with_context(|| format!("should be only one {} handler", route))
+/// Find the name of the handler function for `method` and `route` (as in
+/// `#[get("/route")]`) from source code.
+pub(crate) fn find_handler_function_name(
+    syntax_tree: &syn::File,
+    method: &str,
+    route: &str,
+) -> Result<String> {
+    let mut visitor = FnVisitor::new(method, route);
+    visitor.visit_file(syntax_tree);
+
+    highlander(visitor.out)
+        .with_context(|| format!("should be only one {} {} handler", method, route))
 }
 
-struct FnVisitor {
-    searching_for: String,
+struct FnVisitor<'a> {
+    method: &'a str,
+    route: &'a str,
     out: Vec<String>,
 }
 
-impl FnVisitor {
-    fn new(searching_for: String) -> Self {
+impl<'a> FnVisitor<'a> {
+    fn new(method: &'a str, route: &'a str) -> Self {
         FnVisitor {
-            searching_for,
+            method,
+            route,
             out: Vec::default(),
         }
     }
 }
 
-impl<'ast> Visit<'ast> for FnVisitor {
+impl<'ast, 'a> Visit<'ast> for FnVisitor<'a> {
     fn visit_item_fn(&mut self, i: &'ast ItemFn) {
-        for attr in &i.attrs {
-            if attr
-                .to_token_stream()
-                .to_string()
-                .replace(" ", "")
-                .starts_with(&self.searching_for)
-            {
-                self.out.push(i.sig.ident.to_token_stream().to_string());
+        for attr in i.attrs.iter().filter_map(parse_route_attr) {
+            if attr.route == self.route && attr.methods.iter().any(|m| m == self.method) {
+                self.out.push(i.sig.ident.to_string());
             }
         }
     }
 }
 
-/// Find locations of `.service(index)` from source code.
-pub(crate) fn find_service_registration(code: &str) -> Result<Location> {
-    let mut visitor = MethodCallVisitor::new("service".into());
-    if let Ok(syntax_tree) = syn::parse_file(&code) {
-        visitor.visit_file(&syntax_tree);
+/// Where to register a new handler with the app.
+#[derive(Debug)]
+pub(crate) struct ServiceRegistration {
+    pub(crate) at: LineColumn,
+    pub(crate) kind: RegistrationKind,
+    /// The prefix of the `web::scope()` that we're registering into, if any.
+    /// Routes are relative to this.
+    pub(crate) scope: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum RegistrationKind {
+    /// Append `.service(handler)` to an `App` or `Scope` builder.
+    Builder,
+    /// Add `cfg.service(handler);` to a `fn(cfg: &mut web::ServiceConfig)`.
+    Config {
+        cfg: String,
+        /// The function ends in an expression that needs a `;` after it.
+        needs_semicolon: bool,
+    },
+}
+
+/// Find where to register a handler for `route_path`.
+///
+/// This is a `fn app_config(cfg: &mut web::ServiceConfig)` if there is one,
+/// or else the `App` builder inside `HttpServer::new(...)`. If that app is
+/// built by a helper function, or uses `.configure(...)` with a function from
+/// this file, then we follow it. If `route_path` falls under one of the
+/// app's `web::scope(...)`s then we register it there.
+pub(crate) fn find_service_registration(
+    syntax_tree: &syn::File,
+    route_path: &str,
+) -> Result<ServiceRegistration> {
+    if let Some(app_config) = find_item_fn(syntax_tree, "app_config") {
+        return config_registration(app_config, route_path);
     }
-    // FIXME: there has to be a better way to do this.
-    // It currently relies on the fact that most people define theirtests at
-    // the bottom of the file, so the one in main() is seen first.
-    first(visitor.out).context("should be at least one call to .service(...)")
+
+    let mut visitor = HttpServerVisitor::default();
+    visitor.visit_file(syntax_tree);
+    let factory = first(visitor.out).context("Could not find a call to HttpServer::new(...)")?;
+    let app = find_app_builder(syntax_tree, factory, 0)
+        .context("Could not find App::new() inside HttpServer::new(...)")?;
+
+    let configure_fn = method_calls(app)
+        .filter(|call| call.method == "configure")
+        .filter_map(|call| match call.args.first() {
+            Some(Expr::Path(path)) => path.path.get_ident().map(ToString::to_string),
+            _ => None,
+        })
+        .find_map(|name| find_item_fn(syntax_tree, &name));
+    if let Some(configure_fn) = configure_fn {
+        return config_registration(configure_fn, route_path);
+    }
+
+    Ok(find_scope(app, route_path).unwrap_or(ServiceRegistration {
+        at: app.span().end(),
+        kind: RegistrationKind::Builder,
+        scope: None,
+    }))
 }
 
-struct MethodCallVisitor {
-    searching_for: String,
-    out: Vec<Location>,
+fn config_registration(item_fn: &ItemFn, route_path: &str) -> Result<ServiceRegistration> {
+    let cfg = match item_fn.sig.inputs.first() {
+        Some(FnArg::Typed(PatType { pat, .. })) => match &**pat {
+            Pat::Ident(pat_ident) => pat_ident.ident.to_string(),
+            _ => anyhow::bail!("the ServiceConfig argument should be a plain identifier"),
+        },
+        _ => anyhow::bail!("{}() should take a ServiceConfig", item_fn.sig.ident),
+    };
+    for stmt in &item_fn.block.stmts {
+        if let Stmt::Expr(expr) | Stmt::Semi(expr, _) = stmt {
+            if let Some(scope) = find_scope(expr, route_path) {
+                return Ok(scope);
+            }
+        }
+    }
+    let needs_semicolon = matches!(item_fn.block.stmts.last(), Some(Stmt::Expr(_)));
+    let end = item_fn.block.brace_token.span.end();
+    Ok(ServiceRegistration {
+        at: LineColumn {
+            line: end.line,
+            column: end.column - 1,
+        },
+        kind: RegistrationKind::Config {
+            cfg,
+            needs_semicolon,
+        },
+        scope: None,
+    })
+}
+
+fn find_item_fn<'ast>(syntax_tree: &'ast syn::File, name: &str) -> Option<&'ast ItemFn> {
+    syntax_tree.items.iter().find_map(|item| match item {
+        Item::Fn(item_fn) if item_fn.sig.ident == name => Some(item_fn),
+        _ => None,
+    })
+}
+
+/// Find the `App::new()...` builder in `expr`, looking inside any helper
+/// functions that it calls.
+fn find_app_builder<'ast>(
+    syntax_tree: &'ast syn::File,
+    expr: &'ast Expr,
+    depth: usize,
+) -> Option<&'ast Expr> {
+    let mut visitor = BuilderVisitor::new(&["App", "new"]);
+    visitor.visit_expr(expr);
+    if let Some(app) = visitor.out.into_iter().next() {
+        return Some(app);
+    }
+    if depth > 3 {
+        return None;
+    }
+    let mut visitor = LocalCallVisitor::default();
+    visitor.visit_expr(expr);
+    visitor.out.into_iter().find_map(|name| {
+        let helper = find_item_fn(syntax_tree, &name)?;
+        helper.block.stmts.iter().find_map(|stmt| match stmt {
+            Stmt::Expr(expr) | Stmt::Semi(expr, _) => {
+                find_app_builder(syntax_tree, expr, depth + 1)
+            }
+            Stmt::Local(Local {
+                init: Some((_, expr)),
+                ..
+            }) => find_app_builder(syntax_tree, expr, depth + 1),
+            _ => None,
+        })
+    })
+}
+
+/// Find the `web::scope("/prefix")...` builder in `expr` with the longest
+/// prefix that `route_path` falls under.
+fn find_scope(expr: &Expr, route_path: &str) -> Option<ServiceRegistration> {
+    let mut visitor = BuilderVisitor::new(&["web", "scope"]);
+    visitor.visit_expr(expr);
+    visitor
+        .out
+        .into_iter()
+        .filter_map(|scope| {
+            let prefix = match chain_root(scope) {
+                Expr::Call(call) => match call.args.first() {
+                    Some(Expr::Lit(ExprLit {
+                        lit: Lit::Str(prefix),
+                        ..
+                    })) => prefix.value(),
+                    _ => return None,
+                },
+                _ => return None,
+            };
+            let rest = route_path.strip_prefix(prefix.trim_end_matches('/'))?;
+            if !(rest.is_empty() || rest.starts_with('/')) {
+                return None;
+            }
+            Some(ServiceRegistration {
+                at: scope.span().end(),
+                kind: RegistrationKind::Builder,
+                scope: Some(prefix),
+            })
+        })
+        .max_by_key(|registration| registration.scope.as_ref().map(String::len))
+}
+
+/// Follow a chain of method calls back to the expression that it starts
+/// from.
+fn chain_root(mut expr: &Expr) -> &Expr {
+    while let Expr::MethodCall(call) = expr {
+        expr = &call.receiver;
+    }
+    expr
 }
 
-impl MethodCallVisitor {
-    fn new(searching_for: String) -> Self {
-        MethodCallVisitor {
-            searching_for,
+/// The method calls in a chain, from last to first.
+fn method_calls(mut expr: &Expr) -> impl Iterator<Item = &ExprMethodCall> {
+    std::iter::from_fn(move || match expr {
+        Expr::MethodCall(call) => {
+            expr = &call.receiver;
+            Some(call)
+        }
+        _ => None,
+    })
+}
+
+fn path_ends_with(path: &syn::Path, segments: &[&str]) -> bool {
+    path.segments.len() >= segments.len()
+        && path
+            .segments
+            .iter()
+            .skip(path.segments.len() - segments.len())
+            .map(|s| s.ident.to_string())
+            .eq(segments.iter().copied())
+}
+
+/// Collects the bodies of closures passed to `HttpServer::new(...)`.
+#[derive(Default)]
+struct HttpServerVisitor<'ast> {
+    out: Vec<&'ast Expr>,
+}
+
+impl<'ast> Visit<'ast> for HttpServerVisitor<'ast> {
+    fn visit_expr_call(&mut self, i: &'ast ExprCall) {
+        if let Expr::Path(func) = &*i.func {
+            if path_ends_with(&func.path, &["HttpServer", "new"]) {
+                if let Some(Expr::Closure(closure)) = i.args.first() {
+                    self.out.push(&closure.body);
+                }
+            }
+        }
+        visit_expr_call(self, i);
+    }
+}
+
+/// Collects the outermost builder chains that start with a call to
+/// `constructor`, like `App::new().service(index)`.
+struct BuilderVisitor<'ast> {
+    constructor: &'static [&'static str],
+    out: Vec<&'ast Expr>,
+}
+
+impl<'ast> BuilderVisitor<'ast> {
+    fn new(constructor: &'static [&'static str]) -> Self {
+        BuilderVisitor {
+            constructor,
             out: Vec::default(),
         }
     }
 }
 
-impl<'ast> Visit<'ast> for MethodCallVisitor {
-    fn visit_expr_method_call(&mut self, i: &'ast ExprMethodCall) {
-        if i.method.to_string() == self.searching_for {
-            self.out.push(Location {
-                start: i.dot_token.span().start(),
-                end: i.span().end(),
-            });
+impl<'ast> Visit<'ast> for BuilderVisitor<'ast> {
+    fn visit_expr(&mut self, i: &'ast Expr) {
+        if let Expr::MethodCall(_) | Expr::Call(_) = i {
+            if let Expr::Call(call) = chain_root(i) {
+                if let Expr::Path(func) = &*call.func {
+                    if path_ends_with(&func.path, self.constructor) {
+                        self.out.push(i);
+                        return;
+                    }
+                }
+            }
         }
-        visit_expr_method_call(self, i);
+        visit_expr(self, i);
+    }
+}
+
+/// Collects the names of plain function calls, like `make_app()`.
+#[derive(Default)]
+struct LocalCallVisitor {
+    out: Vec<String>,
+}
+
+impl<'ast> Visit<'ast> for LocalCallVisitor {
+    fn visit_expr_call(&mut self, i: &'ast ExprCall) {
+        if let Expr::Path(func) = &*i.func {
+            if let Some(ident) = func.path.get_ident() {
+                self.out.push(ident.to_string());
+            }
+        }
+        visit_expr_call(self, i);
     }
 }
 
@@ -173,12 +651,16 @@ impl fmt::Debug for Location {
 mod tests {
     use super::*;
 
+    fn parse(code: &str) -> syn::File {
+        parse_file("src/main.rs", code).unwrap()
+    }
+
     #[test]
-    fn test_find_handler_attr() {
+    fn test_find_handler_fns() {
         assert_eq!(
             format!(
                 "{:#?}",
-                find_handler_attr(
+                find_handler_fns(&parse(
                     r#"
 use actix_web::{get, middleware::Logger, web, App, HttpServer, Responder};
 
@@ -187,47 +669,80 @@ async fn index(query: web::Query<HashMap<String, String>>) -> impl Responder {
     IndexTemplate { query }
 }
                     "#
-                )
-                .unwrap()
+                ))
             ),
-            "4,0-4,11"
+            "[\n    4,0-7,1,\n]"
         );
     }
 
     #[test]
-    fn test_find_test_attr() {
+    fn test_find_test_module() {
+        let syntax_tree = parse(
+            r#"
+fn main() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+}
+"#,
+        );
         assert_eq!(
-            format!(
-                "{:#?}",
-                find_test_attr(
-                    r#"
+            format!("{:?}", find_test_module(&syntax_tree).map(|m| m.end)),
+            "Some(LineColumn { line: 7, column: 0 })"
+        );
+        assert_eq!(
+            format!("{:?}", find_fn(&syntax_tree, "main")),
+            "Some(2,0-2,12)"
+        );
+    }
 
+    #[test]
+    fn test_find_test_attr() {
+        let code = r#"
+#[test]
+fn unit() {}
+
+#[cfg(test)]
+mod tests {
     #[actix_rt::test]
     async fn test_faviconico() {
         let mut app = atest::init_service(App::new().service(index)).await;
-
-        let req = atest::TestRequest::with_uri("/favicon.ico").to_request();
-        let resp = atest::call_service(&mut app, req).await;
-
-        assert!(resp.status().is_success());
     }
-
-                    "#
-                )
-                .unwrap()
-            ),
-            "3,4-3,21"
+}
+"#;
+        assert_eq!(
+            format!("{:#?}", find_test_attr(&parse(code)).unwrap()),
+            "7,4-7,21"
         );
+        // Tests outside of the test module don't count.
+        assert!(find_test_attr(&parse("#[test]\nfn unit() {}\n")).is_err());
+    }
+
+    fn registration(code: &str, route_path: &str) -> String {
+        let registration = find_service_registration(&parse(code), route_path).unwrap();
+        format!(
+            "{},{} {:?} {:?}",
+            registration.at.line, registration.at.column, registration.kind, registration.scope
+        )
     }
 
     #[test]
     fn test_find_service_registration() {
         assert_eq!(
-            format!(
-                "{:#?}",
-                find_service_registration(
-                    r#"
+            registration(
+                r#"
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::{http, test as atest, web::Bytes};
 
+    #[actix_rt::test]
+    async fn test() {
+        // Please don't tell me about this one:
+        let mut app = atest::init_service(App::new().service(index)).await;
+    }
+}
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
@@ -240,24 +755,164 @@ async fn main() -> std::io::Result<()> {
         .run()
         .await
 }
+"#,
+                "/favicon.ico"
+            ),
+            "20,77 Builder None"
+        );
+    }
 
+    #[test]
+    fn test_find_service_registration_in_scope() {
+        assert_eq!(
+            registration(
+                r#"
+fn make_app() -> App<AppEntry, Body> {
+    let app = App::new()
+        .service(index)
+        .service(web::scope("/api").service(users))
+        .service(web::scope("/apiv2/").service(users));
+    app
+}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use actix_web::{http, test as atest, web::Bytes};
+#[actix_rt::main]
+async fn main() -> std::io::Result<()> {
+    HttpServer::new(|| make_app()).bind("127.0.0.1:8080")?.run().await
+}
+"#,
+                "/api/users/42"
+            ),
+            "5,50 Builder Some(\"/api\")"
+        );
+    }
+
+    #[test]
+    fn test_find_service_registration_with_configure() {
+        let code = r#"
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(index).service(web::scope("/api").service(users))
+}
+
+#[actix_rt::main]
+async fn main() -> std::io::Result<()> {
+    HttpServer::new(|| App::new().configure(routes))
+        .bind("127.0.0.1:8080")?
+        .run()
+        .await
+}
+"#;
+        assert_eq!(
+            registration(code, "/favicon.ico"),
+            "4,0 Config { cfg: \"cfg\", needs_semicolon: true } None"
+        );
+        assert_eq!(
+            registration(code, "/api/users"),
+            "3,64 Builder Some(\"/api\")"
+        );
+    }
 
+    #[test]
+    fn test_parse_file_error() {
+        assert_eq!(
+            parse_file("src/main.rs", "fn main() {\n    let x = ;\n")
+                .unwrap_err()
+                .to_string(),
+            "could not parse src/main.rs:1:11: unbalanced brackets or unterminated literal"
+        );
+        assert_eq!(
+            parse_file("src/main.rs", "fn main() {}\nconst X: &str = \"oops;\n")
+                .unwrap_err()
+                .to_string(),
+            "could not parse src/main.rs:2:17: unbalanced brackets or unterminated literal"
+        );
+        assert_eq!(
+            parse_file("src/main.rs", "fn main() {\n    let x = ;\n}\n")
+                .unwrap_err()
+                .to_string(),
+            "could not parse src/main.rs:2:13: expected expression"
+        );
+    }
+
+    #[test]
+    fn test_parse_file_leniently() {
+        let syntax_tree = parse(
+            r#"
+#[get("/")]
+async fn index() -> impl Responder {
+    let x = ;
+}
+
+#[cfg(test)]
+mod tests {
     #[actix_rt::test]
-    async fn test() {
-        // Please don't tell me about this one:
+    async fn test_index() {
         let mut app = atest::init_service(App::new().service(index)).await;
     }
+
+    fn half_written() -> {}
 }
-                    "#
-                )
-                .unwrap()
-            ),
-            "10,62-10,77"
+"#,
+        );
+        assert_eq!(
+            format!("{:?}", find_test_attr(&syntax_tree).unwrap()),
+            "9,4-9,21"
+        );
+    }
+
+    #[test]
+    fn test_parse_route_attr() {
+        let attr = |code: &str| {
+            let item: ItemFn = syn::parse_str(&format!("{} fn handler() {{}}", code)).unwrap();
+            parse_route_attr(&item.attrs[0]).map(|attr| (attr.methods, attr.route, attr.args.len()))
+        };
+        assert_eq!(
+            attr(r#"#[get("/")]"#),
+            Some((vec!["GET".to_string()], "/".to_string(), 0))
+        );
+        assert_eq!(
+            attr(r#"#[actix_web::post("/hello world")]"#),
+            Some((vec!["POST".to_string()], "/hello world".to_string(), 0))
+        );
+        assert_eq!(
+            attr(r#"#[get("/", wrap = "Logger::default()")]"#),
+            Some((vec!["GET".to_string()], "/".to_string(), 1))
+        );
+        assert_eq!(
+            attr(r#"#[route("/", method = "GET", method = "head")]"#),
+            Some((
+                vec!["GET".to_string(), "HEAD".to_string()],
+                "/".to_string(),
+                0
+            ))
+        );
+        assert_eq!(attr(r#"#[actix_rt::test]"#), None);
+        assert_eq!(attr(r#"#[rocket::get("/")]"#), None);
+    }
+
+    #[test]
+    fn test_find_handler_function_name() {
+        let syntax_tree = parse(
+            r#"
+#[get("/")]
+async fn index() -> impl Responder {
+    "Hello world!"
+}
+
+#[actix_web::get("/", wrap = "Logger::default()")]
+async fn index_with_logging() -> impl Responder {
+    "Hello world!"
+}
+
+#[post("/")]
+async fn create() -> impl Responder {
+    "Hello world!"
+}
+"#,
+        );
+        assert!(find_handler_function_name(&syntax_tree, "GET", "/").is_err());
+        assert_eq!(
+            find_handler_function_name(&syntax_tree, "POST", "/").unwrap(),
+            "create"
         );
     }
 }
@@ -0,0 +1,316 @@
+//! Keeping secrets out of the code that we generate.
+//!
+//! Traces are redacted as soon as they are fetched, so nothing downstream
+//! sees the originals. Secrets are swapped for placeholders rather than
+//! dropped, so requests keep their shape and the tests still make sense.
+//!
+//! On top of the built-in rules, `.cargo-ngrok/config.toml` can list more
+//! things to redact:
+//!
+//! ```toml
+//! [redact]
+//! headers = ["x-tenant-key"]
+//! names = ["ssn"]
+//! patterns = ["sk_live_[0-9a-zA-Z]+"]
+//! ```
+
+use crate::config::string_list;
+use crate::http::{self, Headers};
+use crate::list::RequestTrace;
+use anyhow::{Context, Result};
+use regex::Regex;
+use toml_edit::DocumentMut;
+
+pub(crate) const PLACEHOLDER: &str = "REDACTED";
+
+const SECRET_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    "x-auth-token",
+    "x-csrf-token",
+    "x-xsrf-token",
+];
+
+/// Query parameters, form fields and JSON keys are secret if their name ends
+/// with one of these, like `access_token` or `new_password`.
+const SECRET_NAME_SUFFIXES: &[&str] = &[
+    "password",
+    "passwd",
+    "secret",
+    "token",
+    "api_key",
+    "apikey",
+    "signature",
+];
+
+#[derive(Debug)]
+pub(crate) struct Redactor {
+    /// Extra header names, lowercased.
+    headers: Vec<String>,
+    /// Extra query parameter, form field and JSON key names.
+    names: Vec<String>,
+    /// Anything that these match is redacted, wherever it is.
+    patterns: Vec<Regex>,
+}
+
+impl Redactor {
+    /// The built-in rules, plus any from the `[redact]` table of the config.
+    pub(crate) fn from_config(config: &DocumentMut) -> Result<Self> {
+        let patterns = string_list(config, "redact", "patterns")?
+            .iter()
+            .map(|pattern| Regex::new(pattern).with_context(|| format!("compiling {:?}", pattern)))
+            .collect::<Result<_>>()?;
+        Ok(Redactor {
+            headers: string_list(config, "redact", "headers")?
+                .iter()
+                .map(|name| name.to_ascii_lowercase())
+                .collect(),
+            names: string_list(config, "redact", "names")?
+                .iter()
+                .map(|name| normalize_name(name))
+                .collect(),
+            patterns,
+        })
+    }
+
+    pub(crate) fn redact_trace(&self, trace: RequestTrace) -> Result<RequestTrace> {
+        trace.rewrite(
+            |uri| self.redact_uri(uri),
+            |headers, body| self.redact_message(headers, body),
+        )
+    }
+
+    fn is_secret_header(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        SECRET_HEADERS.contains(&name.as_str()) || self.headers.contains(&name)
+    }
+
+    fn is_secret_name(&self, name: &str) -> bool {
+        let name = normalize_name(name);
+        SECRET_NAME_SUFFIXES
+            .iter()
+            .any(|suffix| name.ends_with(suffix))
+            || self.names.contains(&name)
+    }
+
+    fn redact_text(&self, text: &str) -> String {
+        self.patterns
+            .iter()
+            .fold(text.to_string(), |text, pattern| {
+                pattern.replace_all(&text, PLACEHOLDER).into_owned()
+            })
+    }
+
+    fn redact_uri(&self, uri: &str) -> String {
+        let uri = match uri.split_once('?') {
+            Some((path, query)) => format!("{}?{}", path, self.redact_pairs(query)),
+            None => uri.to_string(),
+        };
+        self.redact_text(&uri)
+    }
+
+    /// `a=1&password=hunter2` -> `a=1&password=REDACTED`
+    fn redact_pairs(&self, pairs: &str) -> String {
+        pairs
+            .split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some((name, _)) if self.is_secret_name(name) => format!("{}={}", name, PLACEHOLDER),
+                _ => pair.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+
+    fn redact_message(&self, headers: &mut Headers, body: &mut Vec<u8>) {
+        let is_json = http::is_json(headers);
+        let is_form = http::header(headers, "content-type").is_some_and(|content_type| {
+            content_type
+                .to_ascii_lowercase()
+                .starts_with("application/x-www-form-urlencoded")
+        });
+
+        for (name, value) in headers.iter_mut() {
+            let text = String::from_utf8_lossy(value).into_owned();
+            let text = match self.is_secret_header(name) {
+                true => header_placeholder(name, &text),
+                false => text,
+            };
+            *value = self.redact_text(&text).into_bytes();
+        }
+
+        if is_json {
+            if let Ok(json) = serde_json::from_slice::<serde_json::Value>(body) {
+                let mut redacted = json.clone();
+                self.redact_json(&mut redacted);
+                // Re-serializing loses the original layout, so only do it if
+                // we have to.
+                if redacted != json {
+                    *body = serde_json::to_vec(&redacted).expect("serializing a Value can't fail");
+                }
+                return;
+            }
+        }
+        if let Ok(text) = std::str::from_utf8(body) {
+            let text = match is_form {
+                true => self.redact_pairs(text),
+                false => text.to_string(),
+            };
+            *body = self.redact_text(&text).into_bytes();
+        }
+    }
+
+    fn redact_json(&self, value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    let is_scalar = !(value.is_object() || value.is_array() || value.is_null());
+                    if is_scalar && self.is_secret_name(key) {
+                        *value = PLACEHOLDER.into();
+                    } else {
+                        self.redact_json(value);
+                    }
+                }
+            }
+            serde_json::Value::Array(values) => {
+                values.iter_mut().for_each(|value| self.redact_json(value));
+            }
+            serde_json::Value::String(text) => *text = self.redact_text(text),
+            _ => {}
+        }
+    }
+}
+
+/// Whether `text` has had something redacted from it, so tests can't expect
+/// the real server to send it.
+pub(crate) fn is_redacted(text: &str) -> bool {
+    text.contains(PLACEHOLDER)
+}
+
+/// A stand-in for a secret header, keeping the parts that aren't secret.
+fn header_placeholder(name: &str, value: &str) -> String {
+    match name.to_ascii_lowercase().as_str() {
+        // `Bearer abc123` -> `Bearer REDACTED`
+        "authorization" | "proxy-authorization" => match value.split_once(' ') {
+            Some((scheme, _)) => format!("{} {}", scheme, PLACEHOLDER),
+            None => PLACEHOLDER.to_string(),
+        },
+        // `a=1; b=2` -> `a=REDACTED; b=REDACTED`
+        "cookie" => value
+            .split(';')
+            .map(|cookie| match cookie.trim().split_once('=') {
+                Some((name, _)) => format!("{}={}", name, PLACEHOLDER),
+                None => PLACEHOLDER.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("; "),
+        // `a=1; Path=/; HttpOnly` -> `a=REDACTED; Path=/; HttpOnly`
+        "set-cookie" => match value.split_once(';') {
+            Some((cookie, attributes)) => {
+                format!("{};{}", header_placeholder("cookie", cookie), attributes)
+            }
+            None => header_placeholder("cookie", value),
+        },
+        _ => PLACEHOLDER.to_string(),
+    }
+}
+
+/// `X-Api-Key` -> `x_api_key`
+fn normalize_name(name: &str) -> String {
+    name.to_ascii_lowercase().replace('-', "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::list::{Request, Response};
+
+    fn trace(raw_request: &str, raw_response: &str) -> RequestTrace {
+        RequestTrace {
+            id: "1".into(),
+            start: "2020-02-27T10:57:08Z".into(),
+            request: Request {
+                method: "POST".into(),
+                uri: "/login?next=/home&api_key=abc123".into(),
+                raw: base64::encode(raw_request),
+            },
+            response: Response {
+                status_code: 200,
+                raw: base64::encode(raw_response),
+            },
+        }
+    }
+
+    fn decode(raw: &str) -> String {
+        String::from_utf8(base64::decode(raw).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_redact_trace() {
+        let trace = trace(
+            "POST /login?next=/home&api_key=abc123 HTTP/1.1\r\n\
+             Authorization: Bearer abc123\r\n\
+             Cookie: session=abc123; theme=dark\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: 52\r\n\
+             \r\n\
+             {\"user\":{\"name\":\"bob\",\"password\":\"hunter2\"},\"age\":3}",
+            "HTTP/1.1 200 OK\r\n\
+             Set-Cookie: session=abc123; Path=/; HttpOnly\r\n\
+             Content-Type: application/x-www-form-urlencoded\r\n\
+             Content-Length: 32\r\n\
+             \r\n\
+             access_token=abc123&expires=3600",
+        );
+        let redactor = Redactor::from_config(&DocumentMut::new()).unwrap();
+        let trace = redactor.redact_trace(trace).unwrap();
+        assert_eq!(trace.request.uri, "/login?next=/home&api_key=REDACTED");
+        assert_eq!(
+            decode(&trace.request.raw),
+            "POST /login?next=/home&api_key=REDACTED HTTP/1.1\r\n\
+             Authorization: Bearer REDACTED\r\n\
+             Cookie: session=REDACTED; theme=REDACTED\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: 53\r\n\
+             \r\n\
+             {\"age\":3,\"user\":{\"name\":\"bob\",\"password\":\"REDACTED\"}}"
+        );
+        assert_eq!(
+            decode(&trace.response.raw),
+            "HTTP/1.1 200 OK\r\n\
+             Set-Cookie: session=REDACTED; Path=/; HttpOnly\r\n\
+             Content-Type: application/x-www-form-urlencoded\r\n\
+             Content-Length: 34\r\n\
+             \r\n\
+             access_token=REDACTED&expires=3600"
+        );
+    }
+
+    #[test]
+    fn test_redact_with_config() {
+        let config = r#"
+            [redact]
+            headers = ["X-Tenant"]
+            names = ["SSN"]
+            patterns = ["sk_live_[0-9a-zA-Z]+"]
+            "#;
+        let redactor = Redactor::from_config(&config.parse().unwrap()).unwrap();
+        assert!(redactor.is_secret_header("x-tenant"));
+        assert_eq!(
+            redactor.redact_uri("/charge?ssn=123&card=sk_live_abc1"),
+            "/charge?ssn=REDACTED&card=REDACTED"
+        );
+
+        let mut json = serde_json::json!({"note": "key sk_live_abc1", "items": [{"ssn": 123}]});
+        redactor.redact_json(&mut json);
+        assert_eq!(
+            json,
+            serde_json::json!({"note": "key REDACTED", "items": [{"ssn": "REDACTED"}]})
+        );
+
+        let config = "[redact]\npatterns = [\"(\"]\nheaders = \"x-tenant\"";
+        assert!(Redactor::from_config(&config.parse().unwrap()).is_err());
+    }
+}
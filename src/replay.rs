@@ -0,0 +1,789 @@
+//! Replaying traces against a running server, and comparing what comes back
+//! with what ngrok recorded.
+
+use crate::http::{HttpRequest, HttpResponse};
+use crate::list::{
+    fetch_trace, fetch_traces, ngrok_replay, tunnel_address, RequestTrace, StatusFilter,
+};
+use anyhow::{Context, Result};
+use futures::stream::{self, StreamExt};
+use similar::TextDiff;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::time::Duration;
+use structopt::StructOpt;
+use tokio::time::Instant;
+
+/// Headers that the client works out for itself, rather than copying them
+/// from the trace. The body is sent decoded, so it has no content encoding.
+const SKIPPED_REQUEST_HEADERS: &[&str] = &[
+    "host",
+    "content-length",
+    "transfer-encoding",
+    "content-encoding",
+    "connection",
+    "keep-alive",
+];
+
+/// How long to wait for ngrok to record a request that it has replayed.
+const NGROK_REPLAY_TIMEOUT: Duration = Duration::from_secs(10);
+const NGROK_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Headers about how a response was sent, rather than what it says. Bodies
+/// are compared after decoding, so these would only be noise.
+const FRAMING_HEADERS: &[&str] = &[
+    "content-length",
+    "transfer-encoding",
+    "connection",
+    "keep-alive",
+];
+
+/// Headers that change on every response, so they're never compared.
+const UNSTABLE_HEADERS: &[&str] = &["date", "server"];
+
+// Options for where to replay traces, and how to compare the responses.
+#[derive(StructOpt, Debug)]
+pub struct TargetOptions {
+    /// the server to send requests to, like `http://localhost:8080`
+    ///
+    /// Defaults to wherever ngrok is forwarding requests to.
+    #[structopt(long)]
+    target: Option<String>,
+
+    /// leave these headers out when comparing responses, as well as `Date`
+    /// and `Server`
+    #[structopt(long, use_delimiter = true)]
+    ignore_header: Vec<String>,
+}
+
+impl TargetOptions {
+    /// The url to put request uris onto, without a trailing `/`.
+    async fn base_url(&self) -> Result<String> {
+        let target = match &self.target {
+            Some(target) => target.clone(),
+            None => tunnel_address()
+                .await
+                .context("finding the server to replay to (try --target)")?,
+        };
+        let target = match target.contains("://") {
+            true => target,
+            false => format!("http://{}", target),
+        };
+        Ok(target.trim_end_matches('/').to_string())
+    }
+}
+
+// Options for replaying a single trace.
+#[derive(StructOpt, Debug)]
+pub struct ReplayOptions {
+    #[structopt(flatten)]
+    target: TargetOptions,
+
+    /// how many times to send the request
+    #[structopt(long, default_value = "1")]
+    times: u32,
+
+    /// replay through the ngrok tunnel, rather than straight to the server
+    ///
+    /// This asks ngrok to send the request again, so it goes through the
+    /// public url, including TLS termination and host headers.
+    #[structopt(long, conflicts_with = "target")]
+    via_ngrok: bool,
+
+    /// the ngrok tunnel to replay through (with --via-ngrok)
+    ///
+    /// Defaults to the one that the request came in on.
+    #[structopt(long, requires = "via-ngrok")]
+    tunnel_name: Option<String>,
+}
+
+// Options for replaying lots of traces.
+#[derive(StructOpt, Debug)]
+pub struct ReplayAllOptions {
+    #[structopt(flatten)]
+    target: TargetOptions,
+
+    /// only replay traces with this response status (e.g. `503`, `4xx` or
+    /// `500-504`)
+    #[structopt(long)]
+    status: Option<StatusFilter>,
+
+    /// only replay traces with this request method
+    #[structopt(long)]
+    method: Option<String>,
+
+    /// only replay traces whose path starts with this
+    #[structopt(long)]
+    path: Option<String>,
+
+    /// how many requests to have in flight at once
+    ///
+    /// Results are still reported in the order that the requests came in.
+    #[structopt(long, default_value = "1")]
+    concurrency: usize,
+
+    /// leave the same gaps between requests as there were originally
+    #[structopt(long)]
+    preserve_timing: bool,
+}
+
+impl ReplayAllOptions {
+    fn matches(&self, trace: &RequestTrace) -> bool {
+        // ngrok's own error pages say nothing about the server.
+        !trace.response.is_from_ngrok()
+            && self
+                .status
+                .is_none_or(|status| status.matches(trace.response.status_code))
+            && self
+                .method
+                .as_ref()
+                .is_none_or(|method| method.eq_ignore_ascii_case(&trace.request.method))
+            && self
+                .path
+                .as_ref()
+                .is_none_or(|path| trace.request.route_path().starts_with(path.as_str()))
+    }
+}
+
+/// Replay the trace with this `id`.
+pub async fn replay(id: &str, options: &ReplayOptions) -> Result<()> {
+    let trace = fetch_trace(id).await?;
+    replay_trace(&trace, options).await
+}
+
+/// Replay the newest trace with a matching status.
+pub async fn replay_latest(status: StatusFilter, options: &ReplayOptions) -> Result<()> {
+    // Not skipping handled traces, because checking them is the point.
+    let trace = fetch_traces()
+        .await?
+        .into_iter()
+        .find(|trace| status.matches_trace(trace))
+        .with_context(|| format!("no traces found for status {}", status))?;
+    replay_trace(&trace, options).await
+}
+
+async fn replay_trace(trace: &RequestTrace, options: &ReplayOptions) -> Result<()> {
+    let base_url = match options.via_ngrok {
+        true => None,
+        false => Some(options.target.base_url().await?),
+    };
+    let client = client()?;
+    let ignored_headers = &options.target.ignore_header;
+
+    let mut mismatches = 0;
+    for i in 1..=options.times {
+        let mut label = format!("{}, replay {} of {}", describe(trace), i, options.times);
+        let differences = match &base_url {
+            Some(base_url) => replay_once(&client, base_url, trace, ignored_headers).await?,
+            None => {
+                let replayed = replay_via_ngrok(trace, options.tunnel_name.as_deref()).await?;
+                label = format!("{} (via ngrok, as {})", label, replayed.id);
+                diff_traces(trace, &replayed, ignored_headers)?
+            }
+        };
+        print_outcome(&label, &differences);
+        if !differences.is_empty() {
+            mismatches += 1;
+        }
+    }
+    if mismatches > 0 {
+        anyhow::bail!(
+            "{} of {} replays didn't match the recorded response",
+            mismatches,
+            options.times
+        );
+    }
+    Ok(())
+}
+
+/// Replay every matching trace, oldest first, and summarise what changed.
+pub async fn replay_all(options: &ReplayAllOptions) -> Result<()> {
+    let mut traces: Vec<_> = fetch_traces()
+        .await?
+        .into_iter()
+        .filter(|trace| options.matches(trace))
+        .collect();
+    // ngrok lists the newest first.
+    traces.reverse();
+    if traces.is_empty() {
+        anyhow::bail!("no traces to replay");
+    }
+    let offsets = match options.preserve_timing {
+        true => start_offsets(&traces)?,
+        false => vec![Duration::from_secs(0); traces.len()],
+    };
+    let base_url = options.target.base_url().await?;
+    let client = client()?;
+
+    let started = Instant::now();
+    let mut outcomes = stream::iter(traces.iter().zip(offsets))
+        .map(|(trace, offset)| {
+            let client = &client;
+            let base_url = &base_url;
+            async move {
+                tokio::time::delay_until(started + offset).await;
+                replay_once(client, base_url, trace, &options.target.ignore_header).await
+            }
+        })
+        .buffered(options.concurrency.max(1));
+
+    let mut changed_endpoints = BTreeMap::new();
+    let (mut matched, mut differed, mut failed) = (0, 0, 0);
+    for trace in &traces {
+        let outcome = outcomes.next().await.expect("one outcome per trace");
+        match outcome {
+            Ok(differences) => {
+                print_outcome(&describe(trace), &differences);
+                if differences.is_empty() {
+                    matched += 1;
+                    continue;
+                }
+                differed += 1;
+                let endpoint = format!("{} {}", trace.request.method, trace.request.route_path());
+                changed_endpoints
+                    .entry(endpoint)
+                    .or_insert_with(BTreeSet::new)
+                    .extend(differences.iter().map(Difference::kind));
+            }
+            Err(e) => {
+                println!("{}: failed: {:#}", describe(trace), e);
+                failed += 1;
+            }
+        }
+    }
+
+    println!(
+        "\n{} replayed: {} matched, {} differed, {} failed",
+        traces.len(),
+        matched,
+        differed,
+        failed
+    );
+    if !changed_endpoints.is_empty() {
+        println!("endpoints that changed:");
+        for (endpoint, kinds) in &changed_endpoints {
+            let kinds: Vec<_> = kinds.iter().copied().collect();
+            println!("  {} ({})", endpoint, kinds.join(", "));
+        }
+    }
+    if differed + failed > 0 {
+        anyhow::bail!(
+            "{} of {} replays didn't match the recorded response",
+            differed + failed,
+            traces.len()
+        );
+    }
+    Ok(())
+}
+
+/// Have ngrok replay `trace`, and wait for it to record the new one.
+async fn replay_via_ngrok(trace: &RequestTrace, tunnel_name: Option<&str>) -> Result<RequestTrace> {
+    let seen: HashSet<_> = fetch_traces()
+        .await?
+        .into_iter()
+        .map(|trace| trace.id)
+        .collect();
+    let replayed_at = chrono::DateTime::<chrono::Utc>::from(std::time::SystemTime::now());
+    ngrok_replay(&trace.id, tunnel_name).await?;
+
+    let deadline = Instant::now() + NGROK_REPLAY_TIMEOUT;
+    loop {
+        tokio::time::delay_for(NGROK_POLL_INTERVAL).await;
+        // ngrok might not be able to show us a request that it's still
+        // sending, so only give up on errors at the deadline.
+        let found = fetch_traces()
+            .await
+            .map(|traces| find_new_trace(traces, &seen, replayed_at.timestamp(), trace));
+        match found {
+            Ok(Some(replayed)) => return Ok(replayed),
+            Ok(None) | Err(_) if Instant::now() < deadline => continue,
+            Ok(None) => anyhow::bail!(
+                "ngrok didn't record the replayed request within {:?}",
+                NGROK_REPLAY_TIMEOUT
+            ),
+            Err(e) => return Err(e).context("waiting for ngrok to record the replayed request"),
+        }
+    }
+}
+
+/// The newest trace that isn't one of the `seen` ones, for the same request
+/// as `original`, that started no earlier than `replayed_at`.
+///
+/// A real request for the same uri could come in while we wait, so the body
+/// has to match too. `replayed_at` is in seconds since the epoch, because
+/// ngrok's start times might not be any more precise than that.
+fn find_new_trace(
+    traces: Vec<RequestTrace>,
+    seen: &HashSet<String>,
+    replayed_at: i64,
+    original: &RequestTrace,
+) -> Option<RequestTrace> {
+    let body = |trace: &RequestTrace| {
+        trace
+            .request
+            .parse()
+            .ok()
+            .map(|request| request.bytes().to_vec())
+    };
+    let original_body = body(original);
+    traces.into_iter().find(|trace| {
+        !seen.contains(&trace.id)
+            && trace.request.method == original.request.method
+            && trace.request.uri == original.request.uri
+            && chrono::DateTime::parse_from_rfc3339(&trace.start)
+                .is_ok_and(|start| start.timestamp() >= replayed_at)
+            && body(trace) == original_body
+    })
+}
+
+/// How long after the first trace each trace started.
+fn start_offsets(traces: &[RequestTrace]) -> Result<Vec<Duration>> {
+    let starts = traces
+        .iter()
+        .map(|trace| {
+            chrono::DateTime::parse_from_rfc3339(&trace.start).with_context(|| {
+                format!(
+                    "trace {} has no usable start time, so its timing can't be preserved",
+                    trace.id
+                )
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(starts
+        .iter()
+        .map(|start| {
+            // Out-of-order starts are sent straight away.
+            (*start - starts[0]).to_std().unwrap_or_default()
+        })
+        .collect())
+}
+
+fn describe(trace: &RequestTrace) -> String {
+    format!(
+        "{} {} ({})",
+        trace.request.method, trace.request.uri, trace.id
+    )
+}
+
+fn print_outcome(label: &str, differences: &[Difference]) {
+    let verdict = match differences.is_empty() {
+        true => "matches",
+        false => "differs",
+    };
+    println!("{}: {}", label, verdict);
+    for difference in differences {
+        println!("  {}", difference);
+    }
+}
+
+fn client() -> Result<reqwest::Client> {
+    // We want to see redirects, not follow them.
+    Ok(reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()?)
+}
+
+/// Compare the response that ngrok recorded for a replay with the original.
+fn diff_traces(
+    recorded: &RequestTrace,
+    replayed: &RequestTrace,
+    ignored_headers: &[String],
+) -> Result<Vec<Difference>> {
+    Ok(diff_responses(
+        recorded.response.status_code,
+        &recorded.response.parse()?,
+        replayed.response.status_code,
+        &replayed.response.parse()?,
+        ignored_headers,
+    ))
+}
+
+/// Send the traced request to `base_url`, and compare the response with the
+/// recorded one.
+async fn replay_once(
+    client: &reqwest::Client,
+    base_url: &str,
+    trace: &RequestTrace,
+    ignored_headers: &[String],
+) -> Result<Vec<Difference>> {
+    let url = format!("{}{}", base_url, trace.request.uri);
+    let request = trace.request.parse()?;
+    let recorded = trace.response.parse()?;
+    let (status, replayed) = send(client, &trace.request.method, &url, &request).await?;
+    Ok(diff_responses(
+        trace.response.status_code,
+        &recorded,
+        status,
+        &replayed,
+        ignored_headers,
+    ))
+}
+
+async fn send(
+    client: &reqwest::Client,
+    method: &str,
+    url: &str,
+    request: &HttpRequest,
+) -> Result<(u32, HttpResponse)> {
+    let method = reqwest::Method::from_bytes(method.as_bytes())?;
+    let mut builder = client.request(method, url);
+    for (name, value) in request.headers() {
+        if SKIPPED_REQUEST_HEADERS
+            .iter()
+            .any(|skipped| name.eq_ignore_ascii_case(skipped))
+        {
+            continue;
+        }
+        builder = builder.header(name.as_str(), value.as_slice());
+    }
+    let resp = builder
+        .body(request.bytes().to_vec())
+        .send()
+        .await
+        .with_context(|| format!("sending request to {}", url))?;
+
+    let status = resp.status();
+    let start_line = format!("{:?} {}", resp.version(), status);
+    let headers = resp
+        .headers()
+        .iter()
+        .map(|(name, value)| (name.as_str().to_string(), value.as_bytes().to_vec()))
+        .collect();
+    let body = resp.bytes().await?;
+    let response = HttpResponse::from_dechunked(start_line, headers, &body)?;
+    Ok((u32::from(status.as_u16()), response))
+}
+
+/// One way that a replayed response differs from the recorded one.
+#[derive(Debug, PartialEq)]
+enum Difference {
+    Status {
+        recorded: u32,
+        replayed: u32,
+    },
+    Header {
+        name: String,
+        recorded: Option<String>,
+        replayed: Option<String>,
+    },
+    /// A diff of the bodies.
+    Body(String),
+}
+
+impl Difference {
+    fn kind(&self) -> &'static str {
+        match self {
+            Difference::Status { .. } => "status",
+            Difference::Header { .. } => "headers",
+            Difference::Body(_) => "body",
+        }
+    }
+}
+
+impl std::fmt::Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let or_missing = |value: &Option<String>| match value {
+            Some(value) => format!("{:?}", value),
+            None => "(missing)".to_string(),
+        };
+        match self {
+            Difference::Status { recorded, replayed } => {
+                write!(f, "status: {} -> {}", recorded, replayed)
+            }
+            Difference::Header {
+                name,
+                recorded,
+                replayed,
+            } => write!(
+                f,
+                "header {}: {} -> {}",
+                name,
+                or_missing(recorded),
+                or_missing(replayed)
+            ),
+            Difference::Body(diff) => write!(f, "body:\n{}", diff),
+        }
+    }
+}
+
+fn diff_responses(
+    recorded_status: u32,
+    recorded: &HttpResponse,
+    replayed_status: u32,
+    replayed: &HttpResponse,
+    ignored_headers: &[String],
+) -> Vec<Difference> {
+    let mut differences = vec![];
+    if recorded_status != replayed_status {
+        differences.push(Difference::Status {
+            recorded: recorded_status,
+            replayed: replayed_status,
+        });
+    }
+
+    let names: BTreeSet<_> = recorded
+        .headers()
+        .iter()
+        .chain(replayed.headers())
+        .map(|(name, _)| name.to_ascii_lowercase())
+        .filter(|name| {
+            !FRAMING_HEADERS.contains(&name.as_str()) && !UNSTABLE_HEADERS.contains(&name.as_str())
+        })
+        .filter(|name| {
+            !ignored_headers
+                .iter()
+                .any(|ignored| ignored.eq_ignore_ascii_case(name))
+        })
+        .collect();
+    for name in names {
+        let recorded_value = header_value(recorded, &name);
+        let replayed_value = header_value(replayed, &name);
+        if recorded_value != replayed_value {
+            differences.push(Difference::Header {
+                name,
+                recorded: recorded_value,
+                replayed: replayed_value,
+            });
+        }
+    }
+
+    if let Some(diff) = diff_bodies(recorded, replayed) {
+        differences.push(Difference::Body(diff));
+    }
+    differences
+}
+
+/// All of the `name` headers, joined like they could have been sent.
+fn header_value(response: &HttpResponse, name: &str) -> Option<String> {
+    let values: Vec<_> = response
+        .headers()
+        .iter()
+        .filter(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+        .map(|(_, value)| String::from_utf8_lossy(value).into_owned())
+        .collect();
+    match values.is_empty() {
+        true => None,
+        false => Some(values.join(", ")),
+    }
+}
+
+fn diff_bodies(recorded: &HttpResponse, replayed: &HttpResponse) -> Option<String> {
+    if recorded.bytes() == replayed.bytes() {
+        return None;
+    }
+    // JSON is compared by value, so layout and key order don't count.
+    if recorded.is_json() && replayed.is_json() {
+        let parse = |response: &HttpResponse| {
+            serde_json::from_slice::<serde_json::Value>(response.bytes()).ok()
+        };
+        if let (Some(recorded), Some(replayed)) = (parse(recorded), parse(replayed)) {
+            if recorded == replayed {
+                return None;
+            }
+            let pretty = |value| serde_json::to_string_pretty(&value).unwrap() + "\n";
+            return Some(text_diff(&pretty(recorded), &pretty(replayed)));
+        }
+    }
+    match (recorded.text(), replayed.text()) {
+        (Ok(recorded), Ok(replayed)) => Some(text_diff(recorded, replayed)),
+        _ => Some(format!(
+            "binary bodies differ ({} bytes recorded, {} bytes replayed)",
+            recorded.bytes().len(),
+            replayed.bytes().len()
+        )),
+    }
+}
+
+fn text_diff(recorded: &str, replayed: &str) -> String {
+    TextDiff::from_lines(recorded, replayed)
+        .unified_diff()
+        .header("recorded", "replayed")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::list::{Request, Response};
+
+    fn response(raw: &str) -> HttpResponse {
+        HttpResponse::parse(raw.as_bytes()).unwrap()
+    }
+
+    fn trace(start: &str, method: &str, uri: &str, status_code: u32) -> RequestTrace {
+        RequestTrace {
+            id: "1".into(),
+            start: start.into(),
+            request: Request {
+                method: method.into(),
+                uri: uri.into(),
+                raw: String::new(),
+            },
+            response: Response {
+                status_code,
+                raw: String::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_diff_responses() {
+        let options = ReplayOptions::from_iter(&["replay"]);
+        assert!(options.target.ignore_header.is_empty());
+
+        let recorded = response(
+            "HTTP/1.1 500 Internal Server Error\r\n\
+             Date: Mon, 19 Oct 2026 10:00:00 GMT\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: 24\r\n\
+             \r\n\
+             {\"id\": 1, \"name\": \"bob\"}",
+        );
+        let replayed = response(
+            "HTTP/1.1 200 OK\r\n\
+             Date: Mon, 19 Oct 2026 10:05:00 GMT\r\n\
+             Content-Type: application/json\r\n\
+             X-Request-Id: 7\r\n\
+             Transfer-Encoding: chunked\r\n\
+             \r\n\
+             15\r\n{\"name\":\"bob\",\"id\":1}\r\n0\r\n\r\n",
+        );
+        assert_eq!(
+            diff_responses(
+                500,
+                &recorded,
+                200,
+                &replayed,
+                &options.target.ignore_header
+            ),
+            vec![
+                Difference::Status {
+                    recorded: 500,
+                    replayed: 200
+                },
+                Difference::Header {
+                    name: "x-request-id".into(),
+                    recorded: None,
+                    replayed: Some("7".into())
+                },
+            ]
+        );
+        assert_eq!(diff_responses(500, &recorded, 500, &recorded, &[]), vec![]);
+        // Extra headers to ignore don't stop `Date` from being ignored.
+        let options = ReplayOptions::from_iter(&["replay", "--ignore-header=X-Request-Id"]);
+        assert_eq!(
+            diff_responses(
+                200,
+                &recorded,
+                200,
+                &replayed,
+                &options.target.ignore_header
+            ),
+            vec![]
+        );
+
+        let replayed = response("HTTP/1.1 500 Internal Server Error\r\n\r\noops\n");
+        let differences = diff_responses(500, &recorded, 500, &replayed, &[]);
+        assert_eq!(
+            differences
+                .iter()
+                .map(|difference| difference.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "header content-type: \"application/json\" -> (missing)",
+                "body:\n\
+                 --- recorded\n\
+                 +++ replayed\n\
+                 @@ -1 +1 @@\n\
+                 -{\"id\": 1, \"name\": \"bob\"}\n\
+                 \\ No newline at end of file\n\
+                 +oops\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replay_all_filters() {
+        let options = ReplayAllOptions::from_iter(&[
+            "replay-all",
+            "--status=5xx",
+            "--method=post",
+            "--path=/users",
+        ]);
+        let start = "2020-02-27T10:57:08Z";
+        assert!(options.matches(&trace(start, "POST", "/users/1?x=y", 503)));
+        assert!(!options.matches(&trace(start, "GET", "/users/1", 503)));
+        assert!(!options.matches(&trace(start, "POST", "/users/1", 200)));
+        assert!(!options.matches(&trace(start, "POST", "/groups?next=/users", 503)));
+        assert!(
+            ReplayAllOptions::from_iter(&["replay-all"]).matches(&trace(start, "GET", "/", 200))
+        );
+    }
+
+    #[test]
+    fn test_start_offsets() {
+        let traces = vec![
+            trace("2020-02-27T10:57:08Z", "GET", "/", 200),
+            trace("2020-02-27T10:57:09.5Z", "GET", "/", 200),
+            trace("2020-02-27T11:57:07+01:00", "GET", "/", 200),
+        ];
+        assert_eq!(
+            start_offsets(&traces).unwrap(),
+            vec![
+                Duration::from_secs(0),
+                Duration::from_millis(1500),
+                Duration::from_secs(0),
+            ]
+        );
+        assert!(start_offsets(&[trace("", "GET", "/", 200)]).is_err());
+    }
+
+    #[test]
+    fn test_replay_via_ngrok_options() {
+        let options = ReplayOptions::from_iter(&["replay", "--via-ngrok", "--tunnel-name=staging"]);
+        assert!(options.via_ngrok);
+        assert_eq!(options.tunnel_name.as_deref(), Some("staging"));
+        assert!(ReplayOptions::from_iter_safe(&["replay", "--tunnel-name=staging"]).is_err());
+        assert!(ReplayOptions::from_iter_safe(&[
+            "replay",
+            "--via-ngrok",
+            "--target=localhost:8080"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_find_new_trace() {
+        let with = |id: &str, start: &str, uri: &str, body: &str| RequestTrace {
+            id: id.into(),
+            request: Request {
+                method: "POST".into(),
+                uri: uri.into(),
+                raw: base64::encode(&format!(
+                    "POST {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                    uri,
+                    body.len(),
+                    body
+                )),
+            },
+            ..trace(start, "POST", uri, 200)
+        };
+        let original = with("a", "2020-02-27T10:57:08Z", "/users", "x");
+        let replayed_at = chrono::DateTime::parse_from_rfc3339("2020-02-27T10:58:00.5Z")
+            .unwrap()
+            .timestamp();
+        let traces = vec![
+            with("f", "2020-02-27T10:58:01Z", "/groups", "x"),
+            with("e", "2020-02-27T10:58:01Z", "/users", "y"),
+            with("d", "2020-02-27T10:58:00.9Z", "/users", "x"),
+            with("c", "2020-02-27T10:57:59Z", "/users", "x"),
+            with("b", "2020-02-27T10:57:08Z", "/users", "x"),
+            with("a", "2020-02-27T10:57:08Z", "/users", "x"),
+        ];
+        let seen = ["a", "b"].iter().map(|id| id.to_string()).collect();
+        let found = find_new_trace(traces, &seen, replayed_at, &original).unwrap();
+        assert_eq!(found.id, "d");
+        let seen = ["a", "b", "d"].iter().map(|id| id.to_string()).collect();
+        let traces = vec![with("d", "2020-02-27T10:58:00.9Z", "/users", "x")];
+        assert!(find_new_trace(traces, &seen, replayed_at, &original).is_none());
+    }
+}
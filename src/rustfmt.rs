@@ -0,0 +1,37 @@
+//! Running rustfmt over generated code, so that it is ready to commit.
+
+use crate::manifest::{edition, read_manifest, MANIFEST_PATH};
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Format `content` with rustfmt.
+///
+/// rustfmt is run from the current directory, so it picks up the project's
+/// `rustfmt.toml`. The edition comes from `Cargo.toml`, like `cargo fmt` does.
+pub(crate) fn rustfmt(content: &str) -> Result<String> {
+    let mut command = Command::new("rustfmt");
+    if let Some(edition) = read_manifest(MANIFEST_PATH).ok().as_ref().and_then(edition) {
+        command.args(["--edition", edition]);
+    }
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("running rustfmt")?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(content.as_bytes())
+        .context("writing to rustfmt")?;
+    let output = child.wait_with_output().context("running rustfmt")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "rustfmt failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    String::from_utf8(output.stdout).context("reading rustfmt output")
+}
@@ -0,0 +1,98 @@
+//! Remembering which traces have already been turned into code, so that we
+//! don't keep picking the same ones.
+
+use crate::output::{create_parent_dir, OutputOptions};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub(crate) const STATE_PATH: &str = ".cargo-ngrok/state.json";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct State {
+    /// Keyed by trace id.
+    handled: BTreeMap<String, Handled>,
+    /// Test fns, keyed by a hash of the request that they make. Each new
+    /// trace has a new id, but the request might already have a test.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    tested: BTreeMap<String, String>,
+}
+
+/// What a trace was turned into.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub(crate) struct Handled {
+    /// The subcommand that used it, like `new-test`.
+    pub(crate) command: String,
+    /// The handler or test fn that it became.
+    pub(crate) function: String,
+}
+
+impl State {
+    /// Load the state, or start afresh if there isn't any yet.
+    pub(crate) fn load() -> Result<Self> {
+        match std::fs::read_to_string(STATE_PATH) {
+            Ok(content) => {
+                serde_json::from_str(&content).with_context(|| format!("parsing {:?}", STATE_PATH))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(State::default()),
+            Err(e) => Err(e).with_context(|| format!("reading {:?}", STATE_PATH)),
+        }
+    }
+
+    /// Save the state, unless `output` says that we're only looking.
+    pub(crate) fn save(&self, output: &OutputOptions) -> Result<()> {
+        if output.dry_run || output.check {
+            return Ok(());
+        }
+        let content = serde_json::to_string_pretty(self)? + "\n";
+        create_parent_dir(STATE_PATH)?;
+        std::fs::write(STATE_PATH, content).with_context(|| format!("writing {:?}", STATE_PATH))
+    }
+
+    pub(crate) fn handled(&self, trace_id: &str) -> Option<&Handled> {
+        self.handled.get(trace_id)
+    }
+
+    pub(crate) fn is_handled(&self, trace_id: &str) -> bool {
+        self.handled.contains_key(trace_id)
+    }
+
+    pub(crate) fn record(&mut self, trace_id: &str, command: &str, function: &str) {
+        self.handled.insert(
+            trace_id.to_string(),
+            Handled {
+                command: command.to_string(),
+                function: function.to_string(),
+            },
+        );
+    }
+
+    pub(crate) fn tested(&self, request_hash: &str) -> Option<&str> {
+        self.tested.get(request_hash).map(String::as_str)
+    }
+
+    pub(crate) fn record_test(&mut self, request_hash: &str, function: &str) {
+        self.tested
+            .insert(request_hash.to_string(), function.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_round_trip() {
+        let mut state = State::default();
+        state.record("3a1b", "new-test", "test_index_param_boom");
+        let json = serde_json::to_string(&state).unwrap();
+        assert_eq!(
+            json,
+            r#"{"handled":{"3a1b":{"command":"new-test","function":"test_index_param_boom"}}}"#
+        );
+        let state: State = serde_json::from_str(&json).unwrap();
+        assert!(state.is_handled("3a1b"));
+        assert_eq!(state.handled("3a1b").unwrap().command, "new-test");
+        assert!(!state.is_handled("4c2d"));
+    }
+}
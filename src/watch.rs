@@ -0,0 +1,138 @@
+//! Watching for new 404s and 5xxs as they come in.
+
+use crate::list::{list_requests, RequestTrace};
+use crate::new::{new_handler_for_trace, new_test_for_trace, GenerateOptions};
+use crate::state::State;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::time::Duration;
+use structopt::StructOpt;
+
+// Options for `watch`.
+#[derive(StructOpt)]
+pub struct WatchOptions {
+    /// how often to check for new requests, in seconds
+    #[structopt(long, default_value = "1")]
+    interval: u64,
+
+    /// run new-handler for new 404s, and new-test for new 5xxs, rather than
+    /// just suggesting it
+    #[structopt(long)]
+    auto: bool,
+
+    #[structopt(flatten)]
+    generate: GenerateOptions,
+}
+
+/// What to do about a new trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Action {
+    NewHandler,
+    NewTest,
+}
+
+impl Action {
+    fn for_status(status_code: u32) -> Option<Self> {
+        match status_code {
+            404 => Some(Action::NewHandler),
+            500..=599 => Some(Action::NewTest),
+            _ => None,
+        }
+    }
+
+    fn command(self) -> &'static str {
+        match self {
+            Action::NewHandler => "new-handler",
+            Action::NewTest => "new-test",
+        }
+    }
+
+    fn run(self, options: &GenerateOptions, trace: RequestTrace) -> Result<()> {
+        let mut state = State::load()?;
+        match self {
+            Action::NewHandler => new_handler_for_trace(options, trace, &mut state),
+            Action::NewTest => new_test_for_trace(options, &trace, &mut state),
+        }
+    }
+}
+
+pub async fn watch(options: WatchOptions) -> Result<()> {
+    // Only new traces are interesting, so everything that's already there
+    // counts as seen.
+    let mut seen: HashSet<_> = list_requests()
+        .await?
+        .into_iter()
+        .map(|trace| trace.id)
+        .collect();
+    // Each route only needs doing once, however many times it's requested.
+    let mut acted_on = HashSet::new();
+    eprintln!("watching for new 404s and 5xxs (press Ctrl-C to stop)");
+
+    loop {
+        tokio::time::delay_for(Duration::from_secs(options.interval)).await;
+        let traces = match list_requests().await {
+            Ok(traces) => traces,
+            Err(e) => {
+                // ngrok might just be restarting.
+                eprintln!("warning: couldn't list requests: {:#}", e);
+                continue;
+            }
+        };
+        let state = State::load()?;
+        // Oldest first, so things are reported in the order that they happened.
+        for trace in traces.into_iter().rev() {
+            if !seen.insert(trace.id.clone()) || state.is_handled(&trace.id) {
+                continue;
+            }
+            let action = match Action::for_status(trace.response.status_code) {
+                Some(action) => action,
+                None => continue,
+            };
+            let summary = format!(
+                "{} {} {} ({})",
+                trace.response.status_code, trace.request.method, trace.request.uri, trace.id
+            );
+            // Like when the server is restarting. It's not the server's fault,
+            // so it doesn't need a test.
+            if trace.response.is_from_ngrok() {
+                println!("{}: ngrok sent this itself, so skipping it", summary);
+                continue;
+            }
+            let route = (
+                action,
+                trace.request.method.clone(),
+                trace.request.route_path().to_string(),
+            );
+            if acted_on.contains(&route) {
+                println!("{}: seen this route already", summary);
+            } else if options.auto {
+                println!("{}: running `cargo ngrok {}`", summary, action.command());
+                match action.run(&options.generate, trace) {
+                    Ok(()) => {
+                        acted_on.insert(route);
+                    }
+                    // Leave the route to be tried again by the next request
+                    // for it, in case the source was only half written.
+                    Err(e) => eprintln!("error: {} failed: {:#}", action.command(), e),
+                }
+            } else {
+                println!("{}: try `cargo ngrok {}`", summary, action.command());
+                acted_on.insert(route);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_action_for_status() {
+        assert_eq!(Action::for_status(404), Some(Action::NewHandler));
+        assert_eq!(Action::for_status(500), Some(Action::NewTest));
+        assert_eq!(Action::for_status(503), Some(Action::NewTest));
+        assert_eq!(Action::for_status(200), None);
+        assert_eq!(Action::for_status(401), None);
+    }
+}